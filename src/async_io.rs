@@ -0,0 +1,153 @@
+/* gemtext2md, A gemtext to markdown converter, by Martin Keegan
+
+   To the extent (if any) permissible by law, Copyright (C) 2023  Martin Keegan
+
+   This programme is free software; you may redistribute and/or modify it under
+   the terms of the Apache Software Licence v2.0. */
+
+/* The conversion over tokio's async I/O, with the "async" feature. The
+   pipeline is still the thread-per-stage one, so this doesn't make the
+   conversion itself async: convert runs on a thread of its own, as it
+   would for the sync API, and the input and output are carried to and
+   from it in chunks over bounded channels. Only the async side ever waits
+   on the async reader and writer, so the runtime is never blocked, and as
+   the conversion is convert's, the output is exactly the same. */
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::thread;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{convert, Error, Options, Summary};
+
+// how many chunks may be on their way to or from the conversion at once
+const CHUNKS: usize = 16;
+
+// the input, as the conversion thread sees it
+struct ChannelReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => { self.chunk = chunk?; self.pos = 0 },
+                None => return Ok(0)
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// the output, likewise; once the async side has stopped taking it, as when
+// writing has failed, the conversion finds the pipe broken and stops
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "output closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+// convert, reading from an async reader and writing to an async writer
+pub async fn convert_async<R, W>(mut input: R, mut output: W,
+                                 opts: &Options) -> Result<Summary, Error>
+where R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin {
+    let (in_tx, in_rx) = mpsc::channel(CHUNKS);
+    let (out_tx, mut out_rx) = mpsc::channel(CHUNKS);
+    let (done_tx, done_rx) = oneshot::channel();
+
+    let opts = opts.clone();
+    thread::spawn(move || {
+        let reader = ChannelReader { rx: in_rx, chunk: vec![], pos: 0 };
+        let mut writer = BufWriter::new(ChannelWriter(out_tx));
+        let result = convert(Box::new(BufReader::new(reader)), &mut writer, &opts);
+        let _ = done_tx.send(result);
+    });
+
+    // a failure to read is passed on for the conversion to report; if the
+    // conversion stops reading, as at an error, there's no more to send.
+    // Each side drops its end of its channel when it finishes, which the
+    // conversion sees as the end of the input, or as a broken pipe
+    let reading = async move {
+        loop {
+            let chunk = match input.fill_buf().await {
+                Ok([]) => break,
+                Ok(chunk) => Ok(chunk.to_vec()),
+                Err(e) => Err(e)
+            };
+            let failed = chunk.is_err();
+            if let Ok(chunk) = &chunk {
+                input.consume(chunk.len());
+            }
+            if in_tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+
+    let writing = async move {
+        while let Some(chunk) = out_rx.recv().await {
+            output.write_all(&chunk).await?;
+        }
+        output.flush().await
+    };
+
+    let ((), written) = tokio::join!(reading, writing);
+    let result = done_rx.await
+        .unwrap_or_else(|_| Err(Error::Io(io::Error::other("conversion thread panicked"))));
+    // a failure to write comes first, as the conversion then fails for
+    // want of anywhere to write to
+    written?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+    }
+
+    #[test]
+    fn matches_the_sync_conversion() {
+        let input = "# Title\n\nSome text\n=> /a A\n=> /b\n```rust\nfn main() {}\n```\n> q\n";
+        let opts = Options::default();
+
+        let mut sync_out = vec![];
+        convert(Box::new(io::Cursor::new(input.as_bytes().to_vec())), &mut sync_out, &opts)
+            .unwrap();
+        let mut async_out = vec![];
+        run(convert_async(input.as_bytes(), &mut async_out, &opts)).unwrap();
+
+        assert_eq!(async_out, sync_out);
+    }
+
+    // more than the channels hold at once, in both directions
+    #[test]
+    fn converts_large_input() {
+        let input = "=> /a A\n\nsome text\n".repeat(20_000);
+        let mut out = vec![];
+        run(convert_async(input.as_bytes(), &mut out, &Options::default())).unwrap();
+        assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), 80_000);
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        let mut out = vec![];
+        let result = run(convert_async("text\n=>\n".as_bytes(), &mut out, &Options::default()));
+        assert!(matches!(result, Err(Error::Malformed { line: 2, .. })));
+    }
+}
@@ -0,0 +1,156 @@
+/* gemtext2md, A gemtext to markdown converter, by Martin Keegan
+
+   To the extent (if any) permissible by law, Copyright (C) 2023  Martin Keegan
+
+   This programme is free software; you may redistribute and/or modify it under
+   the terms of the Apache Software Licence v2.0. */
+
+/* Fetching the input from a URL given on the commandline, with the "fetch"
+   feature. http:// and https:// are supported, the latter by rustls with
+   the Mozilla root certificates. gemini:// is not: Gemini servers mostly
+   have self-signed certificates, to be trusted on first use, which wants
+   somewhere to remember them. */
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+
+pub fn is_url(arg: &str) -> bool {
+    ["http://", "https://", "gemini://"].iter().any(|s| arg.starts_with(s))
+}
+
+// a connection, plain or over TLS
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+// an http:// or https:// URL, split up
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String
+}
+
+fn parse_url(url: &str) -> Result<Url, String> {
+    let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(r), _) => (false, r),
+        (_, Some(r)) => (true, r),
+        _ => {
+            let scheme = &url[..url.find(':').unwrap_or(0)];
+            return Err(format!("{}: {} isn't supported; fetch it some other way \
+                                and pipe it in", url, scheme));
+        }
+    };
+    let (authority, path) = match rest.find(['/', '?', '#']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+    let path = path.split('#').next().unwrap_or("/");
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) => (h, p.parse().map_err(|_| format!("{}: bad port", url))?),
+        None => (authority, if tls { 443 } else { 80 })
+    };
+    if host.is_empty() {
+        return Err(format!("{}: no host", url));
+    }
+
+    Ok(Url { tls, host: host.to_string(), port, path })
+}
+
+fn connect(url: &Url) -> io::Result<Box<dyn Stream>> {
+    let tcp = TcpStream::connect((url.host.as_str(), url.port))?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    if !url.tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let name = ServerName::try_from(url.host.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(Arc::new(config), name).map_err(io::Error::other)?;
+
+    Ok(Box::new(UntidyClose(StreamOwned::new(conn, tcp))))
+}
+
+// plenty of servers close the connection without the TLS close_notify, which
+// rustls reports as an error; the body of an HTTP/1.0 response ends with the
+// connection either way, so that is taken as the end of it
+struct UntidyClose<S>(S);
+
+impl<S: Read> Read for UntidyClose<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            r => r
+        }
+    }
+}
+
+impl<S: Write> Write for UntidyClose<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+// the body of the resource at url, after following any redirects; the
+// response is read as it is converted, rather than all at once
+pub fn fetch(url: &str) -> Result<Box<dyn BufRead + Send>, String> {
+    let mut url = url.to_string();
+
+    for _ in 0..=MAX_REDIRECTS {
+        let parsed = parse_url(&url)?;
+        let net_error = |e: io::Error| format!("{}: {}", url, e);
+
+        let mut stream = connect(&parsed).map_err(net_error)?;
+        // HTTP/1.0, so that the body isn't chunked and ends with the
+        // connection
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\
+                               User-Agent: gemtext2md\r\n\r\n", parsed.path, parsed.host);
+        stream.write_all(request.as_bytes()).map_err(net_error)?;
+        stream.flush().map_err(net_error)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).map_err(net_error)?;
+        let status = status_line.split_whitespace().nth(1).unwrap_or("");
+
+        let mut location = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).map_err(net_error)? == 0 {
+                break;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("location") {
+                    location = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let scheme = if parsed.tls { "https" } else { "http" };
+        match (status.chars().next(), location) {
+            (Some('2'), _) => return Ok(Box::new(reader)),
+            (Some('3'), Some(l)) if l.starts_with("//") => url = format!("{}:{}", scheme, l),
+            (Some('3'), Some(l)) if l.starts_with('/') =>
+                url = format!("{}://{}:{}{}", scheme, parsed.host, parsed.port, l),
+            (Some('3'), Some(l)) => url = l,
+            _ if status_line.trim().is_empty() => return Err(format!("{}: no response", url)),
+            _ => return Err(format!("{}: {}", url, status_line.trim()))
+        }
+    }
+
+    Err(format!("{}: too many redirects", url))
+}
@@ -0,0 +1,3204 @@
+/* gemtext2md, A gemtext to markdown converter, by Martin Keegan
+
+   To the extent (if any) permissible by law, Copyright (C) 2023  Martin Keegan
+
+   This programme is free software; you may redistribute and/or modify it under
+   the terms of the Apache Software Licence v2.0. */
+
+/* The conversion itself, from Gemtext to Markdown, hopefully in CommonMark
+   format. The commandline filter in main.rs is a thin wrapper round this. */
+
+/* The author is perfectly aware that this code is unidiomatic,
+   inefficient, inelegant, unprincipled, undocumented, depends on
+   loading the whole input stream into memory, makes your curtains
+   fade, etc, etc. It is simply not worth polishing it. */
+
+/* The code below is a fairly bloody-minded hand translation from
+   the OCaml original into unidiomatic Rust, right down to the function
+   names. */
+
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver};
+use std::thread;
+use std::io::{self, BufRead, Read, Write};
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "async")]
+pub use async_io::convert_async;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum HeadingLevel {
+    H1,
+    H2,
+    H3
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Malformed {
+    MLink,
+    MHeading
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Heading(HeadingLevel, String);
+
+// a URL and its optional label
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Link(String, Option<String>);
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Line {
+    PreformattedL(Option<String>, Vec<String>),
+    ParaL(String),
+    LinkL(Link),
+    HeadingL(Heading),
+    QuoteL(String),
+    ListL(String),
+    BlankL,
+    CommentL(String),
+    RawL(String),
+    // the line's text is kept for --mark-malformed
+    MalformedL(Malformed, String)
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Block {
+    PreformattedB(Option<String>, Vec<String>),
+    ParaB(String),
+    LinksB(Vec<Link>),
+    HeadingB(Heading),
+    QuoteB(Vec<String>),
+    ListB(Vec<String>),
+    CommentB(String),
+    RawB(String)
+}
+
+// the variants of Block, without their contents, for selecting by type
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum BlockKind {
+    Preformatted,
+    Paragraph,
+    Links,
+    Heading,
+    Quote,
+    List,
+    Comment,
+    Raw
+}
+
+// how a line stands with respect to preformatted blocks; the toggle lines
+// themselves are passed along so their trailing text can be recovered
+enum PrefTag {
+    Raw,
+    Text,
+    Pre,
+    Open,
+    Close
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum PreAlt {
+    #[default]
+    Drop,
+    Info,
+    Comment,
+    Caption
+}
+
+// what to make of a document's first paragraph
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum LeadParagraph {
+    #[default]
+    Unmarked,
+    Frontmatter,
+    Class
+}
+
+// what to use for the text of a link which has no label
+#[derive(Debug,Clone,Default,PartialEq)]
+pub enum NoLabel {
+    #[default]
+    Url,
+    Path,
+    Fixed(String)
+}
+
+// how links are rendered: as Markdown links, or just their captions
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum LinkStyle {
+    #[default]
+    Markdown,
+    Text
+}
+
+// the line terminator for the output
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum Eol {
+    #[default]
+    Lf,
+    Crlf
+}
+
+// what --indent-code indents code with
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum CodeIndent {
+    #[default]
+    Spaces,
+    Tab
+}
+
+// how --numbered-links numbers the links of each list
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LinkNumbering {
+    PerBlock,
+    Continuous
+}
+
+// where --show-urls shows a link's URL: in its text, or after it
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ShowUrls {
+    Inside,
+    After
+}
+
+// what --sanitize-control does with control characters
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Sanitize {
+    Drop,
+    Replace
+}
+
+// what --links-frontmatter lists of each link
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LinksFrontmatter {
+    Urls,
+    Labelled
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Cp1252
+}
+
+// wrappers for including line numbers, and the byte offset in the input
+// at which the line starts; a Block takes both from its first line
+struct NumString(String, usize, usize);
+struct NumLine(Line, usize, usize);
+struct NumBlock(Block, usize, usize);
+
+// conversion settings, threaded through to whichever stages need them
+#[derive(Debug,Clone,Default)]
+pub struct Options {
+    pub normalize_urls: bool,
+    pub warn_dup_headings: bool,
+    pub exact_dup_headings: bool,
+    pub list_indent: usize,
+    pub pre_alt: PreAlt,
+    pub keep_close_alt: bool,
+    pub only: Option<Vec<BlockKind>>,
+    pub compact: bool,
+    pub lint_links: bool,
+    pub simplify_links: bool,
+    pub stream: bool,
+    pub lenient_headings: bool,
+    pub quiet: bool,
+    pub encoding: Encoding,
+    pub embedded: bool,
+    pub scheme_badge: bool,
+    pub autolink_bare_urls: bool,
+    pub validate: bool,
+    // None is the default, of one blank line between blocks
+    pub block_spacing: Option<usize>,
+    pub unescape_labels: bool,
+    pub lead_paragraph: LeadParagraph,
+    pub strip_ansi: bool,
+    pub link_titles: bool,
+    pub trim_pre_trailing: bool,
+    pub detect_lang: bool,
+    pub number_headings: bool,
+    pub no_label: NoLabel,
+    // given in the frontmatter, when converting a file
+    pub slug: Option<String>,
+    pub dotslash: bool,
+    // written verbatim around the converted blocks
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub links: LinkStyle,
+    pub pre_to_table: bool,
+    // the table of contents goes as deep as toc_depth, 3 if not given,
+    // and indents each level by toc_indent spaces, or 2
+    pub toc: bool,
+    pub toc_depth: Option<usize>,
+    pub toc_indent: Option<usize>,
+    // what toggles preformatted text in the input, if not "```"
+    pub source_fence: Option<String>,
+    pub eol: Eol,
+    pub reset_pre_on_blank: Option<usize>,
+    pub warn_link_run: Option<usize>,
+    pub closed_headings: bool,
+    pub mark_malformed: bool,
+    pub no_links: bool,
+    pub section: Option<usize>,
+    pub indent_code: bool,
+    pub code_indent: CodeIndent,
+    pub max_blocks: Option<usize>,
+    pub numbered_links: Option<LinkNumbering>,
+    pub escape: bool,
+    pub provenance: Option<String>,
+    pub text_schemes: Vec<String>,
+    pub wrap: Option<usize>,
+    pub wrap_indent: usize,
+    pub squeeze_spaces: bool,
+    pub infer_headings: Option<usize>,
+    pub preserve_empty_links: bool,
+    pub smart_links: bool,
+    pub tight_heading_links: bool,
+    pub no_lists: bool,
+    pub links_frontmatter: Option<LinksFrontmatter>,
+    pub dedup_links: bool,
+    pub strip_trailing_links: bool,
+    pub html_source_attrs: bool,
+    pub sanitize_control: Option<Sanitize>,
+    pub sanitize_pre: bool,
+    pub trim_heading_hashes: bool,
+    pub canonical: bool,
+    pub literal_fences: bool,
+    pub byte_offsets: bool,
+    pub sentence_breaks: bool,
+    pub embed_source: bool,
+    pub subtitle: bool,
+    pub preserve_link_whitespace: bool,
+    pub show_urls: Option<ShowUrls>,
+    pub embed_under: Option<usize>,
+    pub progress: Option<usize>
+}
+
+// what convert noticed about the document on the way through
+#[derive(Debug,Default)]
+pub struct Summary {
+    pub wrote_any: bool,
+    pub title: Option<String>,
+    pub link_count: usize
+}
+
+// one level 1 heading and the blocks up to the next, for splitting a
+// document up; whatever comes before the first such heading is a section
+// with no title
+#[derive(Debug)]
+pub struct Section {
+    pub title: Option<String>,
+    pub markdown: Vec<u8>,
+    pub summary: Summary
+}
+
+// how many blocks of each of the main types a document has
+#[derive(Debug,Default)]
+pub struct Counts {
+    pub headings: usize,
+    pub paragraphs: usize,
+    pub links: usize,
+    pub preformatted: usize
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "headings={} paragraphs={} links={} preformatted={}",
+               self.headings, self.paragraphs, self.links, self.preformatted)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Malformed { line: usize, kind: Malformed },
+    Embedding { line: usize, problem: &'static str },
+    NoSuchSection { section: usize, sections: usize }
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Malformed::MLink    => write!(f, "link"),
+            Malformed::MHeading => write!(f, "heading")
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Malformed { line, kind } =>
+                write!(f, "malformed {} at line {}", kind, line),
+            Error::Embedding { line, problem } =>
+                write!(f, "{} at line {}", problem, line),
+            Error::NoSuchSection { section, sections } =>
+                write!(f, "there is no section {}: the last is {}", section, sections)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Error::Io(e) }
+}
+
+impl Heading {
+    pub fn new(level: HeadingLevel, text: &str) -> Self {
+        Heading(level, text.to_string())
+    }
+
+    pub fn level(&self) -> &HeadingLevel { &self.0 }
+
+    pub fn text(&self) -> &str { &self.1 }
+}
+
+impl Link {
+    pub fn new(url: &str, label: Option<&str>) -> Self {
+        Link(url.to_string(), label.map(|l| l.to_string()))
+    }
+
+    pub fn url(&self) -> &str { &self.0 }
+
+    pub fn label(&self) -> Option<&str> { self.1.as_deref() }
+}
+
+impl Block {
+    // the block as it would be rendered with the default options
+    pub fn to_markdown(&self) -> String { self.to_string() }
+}
+
+impl From<String> for Line {
+    // corresponds to OCaml function 'line_of_string : string -> line'
+    //
+    // all the markers are ASCII, so matching on bytes rather than chars
+    // recognises exactly the same lines, without building a Vec per line;
+    // only the first few bytes are looked at, however long the line is
+    fn from(s: String) -> Self {
+        use Line::*;
+        use Malformed::*;
+        use HeadingLevel::*;
+
+        match s.as_bytes() {
+            // links
+            [b'=', b'>']                   => MalformedL(MLink, s),
+            [b'=', b'>', b' ', ..]         => link_of_line(s),
+            [b'=', b'>', ..]               => MalformedL(MLink, s),
+
+            // headings
+            [b'#', b'#', b'#']             => MalformedL(MHeading, s),
+            [b'#', b'#', b'#', b' ']       => MalformedL(MHeading, s),
+            [b'#', b'#', b'#', b' ', ..]   => make_heading(s, H3, 4),
+            [b'#', b'#', b'#', ..]         => make_heading(s, H3, 3),
+
+            [b'#', b'#', _]                => MalformedL(MHeading, s),
+            [b'#', b'#']                   => MalformedL(MHeading, s),
+            [b'#', b'#', b' ', ..]         => make_heading(s, H2, 3),
+            [b'#', b'#', ..]               => MalformedL(MHeading, s),
+
+            [b'#', b' ']                   => MalformedL(MHeading, s),
+            [b'#']                         => MalformedL(MHeading, s),
+            [b'#', b' ', ..]               => make_heading(s, H1, 2),
+            [b'#', ..]                     => MalformedL(MHeading, s),
+
+            // quotes; a bare ">" is an empty line within the quotation
+            [b'>', ..]                     => QuoteL(s[1..].trim().to_string()),
+
+            // list items, which want exactly "* ", so "*emphasis*" is a
+            // paragraph
+            [b'*', b' ', ..]               => ListL(s[2..].trim().to_string()),
+
+            // paragraphs / blanks
+            []                             => BlankL,
+            _                              => ParaL(trim(s))
+        }
+    }
+}
+
+// with --lenient-headings, structural lines may be indented, and with
+// --no-links link lines are just text; "=>" means nothing to Markdown, so
+// it needs no escaping. With --no-lists list items are text too, which
+// string_of_para escapes
+fn line_of_string(s: String, opts: &Options) -> Line {
+    let s = if opts.lenient_headings { s.trim_start().to_string() } else { s };
+    if (opts.no_links && s.starts_with("=>")) || (opts.no_lists && s.starts_with("* ")) {
+        return Line::ParaL(trim(s));
+    }
+    match Line::from(s) {
+        Line::HeadingL(Heading(level, t)) if opts.trim_heading_hashes =>
+            Line::HeadingL(Heading(level, trim_heading_hashes(t))),
+        line => line
+    }
+}
+
+// "Heading ##" is "Heading", where the hashes follow whitespace, as in a
+// closed Markdown heading; "C#" is left alone, as is a heading which is
+// nothing but hashes
+fn trim_heading_hashes(t: String) -> String {
+    let without = t.trim_end_matches('#');
+    match without.strip_suffix(char::is_whitespace) {
+        Some(rest) if without.len() < t.len() && !rest.trim().is_empty() =>
+            rest.trim_end().to_string(),
+        _ => t
+    }
+}
+
+impl fmt::Display for Heading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", heading_chars(&self.0), self.1)
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", string_of_link(self, "*", &Options::default()))
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", string_of_block(self, &Options::default()))
+    }
+}
+
+fn kind_of_block(b: &Block) -> BlockKind {
+    use Block::*;
+
+    match b {
+        PreformattedB(..) => BlockKind::Preformatted,
+        ParaB(_)          => BlockKind::Paragraph,
+        LinksB(_)         => BlockKind::Links,
+        HeadingB(_)       => BlockKind::Heading,
+        QuoteB(_)         => BlockKind::Quote,
+        ListB(_)          => BlockKind::List,
+        CommentB(_)       => BlockKind::Comment,
+        RawB(_)           => BlockKind::Raw
+    }
+}
+
+fn string_of_block(b: &Block, opts: &Options) -> String {
+    use Block::*;
+
+    match b {
+        ParaB(p)            => format!("{}\n\n", string_of_para(p, opts)),
+        PreformattedB(alt, prpr) => string_of_preformatted(alt, prpr, opts),
+        LinksB(ll)          => string_of_links(ll.to_vec(), 1, opts),
+        HeadingB(h)         => format!("{}\n\n", string_of_heading(h, opts)),
+        QuoteB(q)           => string_of_quote(q),
+        ListB(items)        => string_of_list(items, opts),
+        CommentB(c)         => format!("{}\n", html_comment(c)),
+        RawB(r)             => format!("{}\n", r)
+    }
+}
+
+fn string_of_heading(h: &Heading, opts: &Options) -> String {
+    let Heading(level, text) = h;
+    let text = if opts.escape { escape_markdown(text) } else { text.to_string() };
+    let hashes = "#".repeat(output_level(level, opts));
+    if opts.closed_headings {
+        format!("{} {} {}", hashes, text, hashes)
+    } else {
+        format!("{} {}", hashes, text)
+    }
+}
+
+// the level a heading is written out at, from 1 to 6: --embed-under N
+// brings any heading above level N down to it, so that the document can
+// go under a level N-1 heading elsewhere, leaving those below it alone
+fn output_level(level: &HeadingLevel, opts: &Options) -> usize {
+    let n = heading_depth(level) + 1;
+    opts.embed_under.map_or(n, |under| n.max(under))
+}
+
+// heading text is literal, but a renderer might still take "```" in it
+// for code or "[x](y)" for a link; backslashes make sure it doesn't
+fn escape_markdown(s: &str) -> String {
+    s.chars().map(|c| match c {
+        '\\' | '`' | '~' | '[' | ']' | '<' | '>' => format!("\\{}", c),
+        c => c.to_string()
+    }).collect()
+}
+
+// a paragraph may start "* " where a list item was indented, or with
+// --no-lists, "#" where a heading was indented, or "```" with another
+// --source-fence, and is escaped so as not to become a list item, heading
+// or code fence
+fn string_of_para(p: &str, opts: &Options) -> String {
+    let escaped;
+    let marker = ["* ", "#", "```", "~~~"].iter().any(|m| p.starts_with(m));
+    let p = if marker {
+        escaped = format!("\\{}", p);
+        &escaped
+    } else {
+        p
+    };
+    let squeezed;
+    let p = if opts.squeeze_spaces {
+        squeezed = squeeze_spaces(p);
+        &squeezed
+    } else {
+        p
+    };
+    let p = if opts.autolink_bare_urls {
+        autolink_bare_urls(p)
+    } else {
+        p.to_string()
+    };
+    let sentences = if opts.sentence_breaks { sentences_of_para(&p) } else { vec![p] };
+    let lines: Vec<String> = match opts.wrap {
+        Some(width) => sentences.iter().map(|s| wrap_para(s, width, opts.wrap_indent)).collect(),
+        None => sentences
+    };
+    lines.join("\n")
+}
+
+// abbreviations which end in a full stop without ending the sentence
+const ABBREVIATIONS: [&str; 14] = [
+    "Mr.", "Mrs.", "Ms.", "Dr.", "Prof.", "St.", "Jr.", "Sr.", "No.", "vs.", "etc.", "e.g.",
+    "i.e.", "cf."
+];
+
+// split a paragraph into its sentences, for --sentence-breaks, which puts
+// each on a line of its own. A sentence ends with a word ending in ".",
+// "?" or "!", perhaps inside quotes or brackets, when the next word starts
+// with a capital letter. This is cautious rather than thorough: a word
+// which is a known abbreviation, or an initial such as "J.", doesn't end a
+// sentence; nor does a full stop within a word, as in a URL or "3.5"
+fn sentences_of_para(p: &str) -> Vec<String> {
+    let words: Vec<&str> = p.split(' ').collect();
+    let mut sentences = vec![];
+    let mut start = 0;
+
+    for i in 0..words.len().saturating_sub(1) {
+        let word = words[i].trim_end_matches(['"', '\'', ')', ']']);
+        let next = words[i + 1].trim_start_matches(['"', '\'', '(', '[']);
+        let initial = word.len() == 2 && word.starts_with(|c: char| c.is_alphabetic());
+        if word.ends_with(['.', '?', '!']) && !initial && !ABBREVIATIONS.contains(&word)
+            && next.starts_with(char::is_uppercase) {
+            sentences.push(words[start..=i].join(" "));
+            start = i + 1;
+        }
+    }
+    sentences.push(words[start..].join(" "));
+
+    sentences
+}
+
+// runs of spaces made by aligning text by hand become single spaces
+fn squeeze_spaces(p: &str) -> String {
+    p.split(' ').filter(|w| !w.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+// break a paragraph at spaces into lines of at most width characters, the
+// lines after the first indented by indent spaces. A line may not start
+// with punctuation or a digit, which could make it a heading, list item,
+// etc., nor follow a trailing backslash, a hard line break; in those cases
+// it runs over the width instead, as it does when a word is too long
+fn wrap_para(p: &str, width: usize, indent: usize) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+
+    for word in p.split_whitespace() {
+        let len = line.chars().count() + 1 + word.chars().count();
+        let safe = !word.starts_with(|c: char| c.is_ascii_punctuation() || c.is_ascii_digit())
+            && !line.ends_with('\\');
+        if line.is_empty() {
+            line.push_str(word);
+        } else if len > width && safe {
+            lines.push(line);
+            line = format!("{}{}", " ".repeat(indent), word);
+        } else {
+            line.push(' ');
+            line.push_str(word);
+        }
+    }
+    lines.push(line);
+
+    lines.join("\n")
+}
+
+// wrap URLs standing on their own in the text as <url> autolinks, since
+// renderers disagree about whether to link them otherwise; only words
+// which are entirely a URL (give or take brackets and punctuation) are
+// touched, so anything already in link syntax is left alone
+fn autolink_bare_urls(p: &str) -> String {
+    let words: Vec<String> = p.split(' ').map(|word| {
+        let (open, rest) = match word.strip_prefix('(') {
+            Some(r) => ("(", r),
+            None => ("", word)
+        };
+        let url = rest.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+        let close = &rest[url.len()..];
+
+        match url_scheme(url) {
+            Some(scheme) if url[scheme.len()..].len() > 3 &&
+                url[scheme.len()..].starts_with("://") =>
+                format!("{}<{}>{}", open, url, close),
+            _ => word.to_string()
+        }
+    }).collect();
+
+    words.join(" ")
+}
+
+fn string_of_preformatted(alt: &Option<String>, prpr: &[String],
+                          opts: &Options) -> String {
+    let (comment, info) = match (alt, opts.pre_alt) {
+        (Some(a), PreAlt::Comment) => (html_comment(a), ""),
+        (Some(a), PreAlt::Caption) => (format!("**{}**\n\n", a.replace('*', "\\*")), ""),
+        // CommonMark forbids backticks in the info string of a ``` fence
+        (Some(a), PreAlt::Info) if !a.contains('`') => (String::new(), a.as_str()),
+        _ => (String::new(), "")
+    };
+
+    let lines: Vec<String> = prpr.iter().map(|l| {
+        let l = if opts.strip_ansi { strip_ansi(l) } else { l.to_string() };
+        // after strip_ansi, which needs the escapes to find the sequences
+        let l = match opts.sanitize_control {
+            Some(how) if opts.sanitize_pre => sanitize_control(&l, how),
+            _ => l
+        };
+        if opts.trim_pre_trailing { l.trim_end().to_string() } else { l }
+    }).collect();
+
+    if opts.pre_to_table {
+        if let Some(rows) = table_of_lines(&lines) {
+            return format!("{}{}", comment, string_of_table(&rows));
+        }
+    }
+
+    // an indented code block has nowhere to put an info string
+    if opts.indent_code {
+        let indent = match opts.code_indent {
+            CodeIndent::Spaces => "    ",
+            CodeIndent::Tab => "\t"
+        };
+        let indented: Vec<String> = lines.iter().map(|l| {
+            if l.is_empty() { String::new() } else { format!("{}{}", indent, l) }
+        }).collect();
+        return format!("{}{}\n\n", comment, indented.join("\n"));
+    }
+
+    // the fence has to be longer than any run of backticks starting a
+    // line, which --literal-fences and --source-fence let through, or the
+    // line would close it
+    let longest = lines.iter()
+        .map(|l| l.trim_start().chars().take_while(|c| *c == '`').count())
+        .max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}{}\n{}\n{}\n\n", comment, fence, info, lines.join("\n"), fence)
+}
+
+// the cells of a preformatted block which looks like a table, either with
+// its columns divided by "|" or lined up with runs of two or more spaces.
+// This errs on the side of leaving code alone: every line must have the
+// same number of cells, at least two, and none of them empty. "|" is a
+// pipe as often as not, as in "ls | wc -l", so it only divides columns if
+// there is a rule such as "|---|---|", or every line starts and ends with
+// one; a block with "|" on every line which is neither stays code
+fn table_of_lines(lines: &[String]) -> Option<Vec<Vec<String>>> {
+    let lines: Vec<&str> = lines.iter()
+        .map(|l| l.trim_end())
+        .filter(|l| !l.is_empty())
+        .collect();
+    if lines.len() < 2 {
+        return None;
+    }
+
+    let is_rule = |l: &&str| l.contains('-') && l.chars().all(|c| "|-:+ ".contains(c));
+    let framed = |l: &&str| l.trim_start().starts_with('|') && l.ends_with('|') && l.len() > 1;
+    let rows = if lines.iter().all(|l| l.contains('|') || is_rule(l)) {
+        if !lines.iter().any(is_rule) && !lines.iter().all(framed) {
+            return None;
+        }
+        lines.iter()
+            .filter(|l| !is_rule(l))
+            .map(|l| l.trim().trim_start_matches('|').trim_end_matches('|')
+                 .split('|').map(|c| c.trim().to_string()).collect())
+            .collect()
+    } else {
+        table_of_aligned(&lines)?
+    };
+
+    let width = rows.first().map(Vec::len)?;
+    let ok = rows.len() >= 2 && width >= 2 &&
+        rows.iter().all(|r| r.len() == width && r.iter().all(|c| !c.is_empty()));
+    if ok { Some(rows) } else { None }
+}
+
+// split lines at the runs of two or more columns which are blank in all of
+// them, so that "a  b" over "cc dd" is one column, not two
+fn table_of_aligned(lines: &[&str]) -> Option<Vec<Vec<String>>> {
+    let grid: Vec<Vec<char>> = lines.iter().map(|l| l.chars().collect()).collect();
+    let width = grid.iter().map(Vec::len).max()?;
+    let blank = |col: usize| grid.iter().all(|l| l.get(col).is_none_or(|c| *c == ' '));
+
+    // the starts of the columns, after the leading indentation
+    let mut starts = vec![(0..width).find(|c| !blank(*c))?];
+    let mut gap = 0;
+    for col in starts[0]..width {
+        if blank(col) {
+            gap += 1;
+        } else {
+            if gap >= 2 {
+                starts.push(col);
+            }
+            gap = 0;
+        }
+    }
+
+    let rows = grid.iter().map(|l| {
+        starts.iter().enumerate().map(|(i, start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(l.len()).min(l.len());
+            l.get(*start..end).map_or(String::new(), |c| c.iter().collect::<String>())
+                .trim().replace('|', "\\|")
+        }).collect()
+    }).collect();
+
+    Some(rows)
+}
+
+// a GFM table, the first row being the header
+fn string_of_table(rows: &[Vec<String>]) -> String {
+    let row = |r: &Vec<String>| format!("| {} |\n", r.join(" | "));
+    let rule = vec!["---".to_string(); rows[0].len()];
+
+    format!("{}{}{}\n", row(&rows[0]), row(&rule),
+            rows[1..].iter().map(row).collect::<String>())
+}
+
+// ASCII control characters, bar the tab, dropped or replaced with U+FFFD
+fn sanitize_control(s: &str, how: Sanitize) -> String {
+    s.chars().filter_map(|c| match how {
+        _ if !c.is_ascii_control() || c == '\t' => Some(c),
+        Sanitize::Drop => None,
+        Sanitize::Replace => Some('\u{fffd}')
+    }).collect()
+}
+
+// drop ANSI CSI sequences, i.e. ESC [, parameter and intermediate bytes,
+// then a final byte; an unfinished sequence runs to the end of the line
+fn strip_ansi(s: &str) -> String {
+    let mut acc = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' || chars.peek() != Some(&'[') {
+            acc.push(c);
+            continue;
+        }
+        chars.next();
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                break;
+            }
+        }
+    }
+
+    acc
+}
+
+fn string_of_list(items: &[String], opts: &Options) -> String {
+    let items: Vec<String> = items.iter()
+        .map(|i| format!("{}* {}", " ".repeat(opts.list_indent), i).trim_end().to_string())
+        .collect();
+
+    format!("{}\n\n", items.join("\n"))
+}
+
+// each line of the quotation is a paragraph of the blockquote, just as
+// other text lines are paragraphs; an empty line only separates them
+fn string_of_quote(q: &[String]) -> String {
+    let paras: Vec<String> = q.iter()
+        .filter(|l| !l.is_empty())
+        .map(|l| format!("> {}\n", l))
+        .collect();
+
+    if paras.is_empty() {
+        return ">\n\n".to_string();
+    }
+    format!("{}\n", paras.join(">\n"))
+}
+
+// text must not close the comment early, so "-->" is defanged
+fn html_comment(text: &str) -> String {
+    format!("<!-- {} -->\n", text.replace("-->", "--&gt;"))
+}
+
+// the URL as it is written out
+fn url_of_link(url: String, opts: &Options) -> String {
+    let url = if opts.normalize_urls { normalize_url(&url) } else { url };
+    if opts.dotslash && is_plain_relative(&url) { format!("./{}", url) } else { url }
+}
+
+// the marker is "*", or the link's number as in "3."
+fn string_of_link(l: &Link, marker: &str, opts: &Options) -> String {
+    let Link(url, tag) = l.clone();
+    let url = url_of_link(url, opts);
+    let tag = if opts.unescape_labels { tag.map(|t| unescape_label(&t)) } else { tag };
+    // --text-schemes lists schemes to be shown as text; relative links
+    // have no scheme, so always stay links
+    let style = match url_scheme(&url) {
+        Some(s) if opts.text_schemes.iter().any(|t| t.eq_ignore_ascii_case(s)) => LinkStyle::Text,
+        _ => opts.links
+    };
+    if opts.simplify_links && style == LinkStyle::Markdown &&
+        tag.as_deref() == Some(url.as_str()) && url_scheme(&url).is_some() {
+        return format!("{}{} <{}>\n", " ".repeat(opts.list_indent), marker, url);
+    }
+
+    // "text | title" gives the link a title, which shows on hover
+    let (tag, title) = match tag.as_deref().and_then(|t| t.split_once(" | ")) {
+        Some((t, title)) if opts.link_titles => {
+            let t = t.trim();
+            let t = if t.is_empty() { None } else { Some(t.to_string()) };
+            (t, format!(" \"{}\"", title.trim().replace('\\', "\\\\").replace('"', "\\\"")))
+        },
+        _ => (tag, String::new())
+    };
+
+    // a label of nothing but whitespace, as "\t" becomes with
+    // --unescape-labels, would make an empty link, so counts as no label
+    let tag = tag.filter(|t| !t.trim().is_empty());
+    let caption = match (tag, &opts.no_label) {
+        (Some(c), _) => c,
+        (None, NoLabel::Fixed(c)) => c.clone(),
+        (None, NoLabel::Path) => last_path_segment(&url).unwrap_or(&url).to_string(),
+        (None, NoLabel::Url) => url.clone()
+    };
+    let caption = match scheme_badge(&url) {
+        Some(badge) if opts.scheme_badge => format!("[{}] {}", badge, caption),
+        _ => caption
+    };
+    // the label's spacing is kept as it was, but rendering would collapse
+    // a run of spaces to one unless the rest are non-breaking
+    let caption = if opts.preserve_link_whitespace {
+        let mut after_space = false;
+        caption.chars().map(|c| {
+            let s = if c == ' ' && after_space { "&nbsp;".to_string() } else { c.to_string() };
+            after_space = c == ' ';
+            s
+        }).collect()
+    } else {
+        caption
+    };
+
+    // for print, where there's no hovering over a link to see where it
+    // goes; a link captioned with its own URL has no need of it twice
+    let shown = match opts.show_urls {
+        Some(_) if caption == url => None,
+        Some(_) if style == LinkStyle::Text => Some(ShowUrls::After),
+        s => s
+    };
+    let visible = || format!(" ({})", url.replace('[', "\\[").replace(']', "\\]"));
+    let (inside, after) = match shown {
+        Some(ShowUrls::Inside) => (visible(), String::new()),
+        Some(ShowUrls::After) => (String::new(), visible()),
+        None => (String::new(), String::new())
+    };
+
+    match style {
+        LinkStyle::Markdown => format!("{}{} [{}{}]({}{}){}\n", " ".repeat(opts.list_indent),
+                                       marker, caption, inside, url, title, after),
+        // the caption starts the item's text, where "# x" would be a heading
+        LinkStyle::Text => format!("{}{} {}{}\n", " ".repeat(opts.list_indent), marker,
+                                   escape_literal(&caption), after)
+    }
+}
+
+// escaped whitespace in a label becomes a space, as a Markdown link's text
+// can't span lines
+fn unescape_label(s: &str) -> String {
+    s.replace("\\n", " ").replace("\\r", " ").replace("\\t", " ")
+}
+
+// a relative URL which doesn't start with "/", "#", "./" or "../"
+fn is_plain_relative(url: &str) -> bool {
+    url_scheme(url).is_none() &&
+        !["/", "#", "?", "./", "../"].iter().any(|p| url.starts_with(p))
+}
+
+// the last part of a URL's path, ignoring any trailing slash, so
+// "/notes/2023/" gives "2023"; None if the path is empty or just "/"
+fn last_path_segment(url: &str) -> Option<&str> {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let path = match url.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => url
+    };
+
+    path.trim_end_matches('/').rsplit('/').next().filter(|s| !s.is_empty())
+}
+
+// a marker for links that won't open in an ordinary web browser
+fn scheme_badge(url: &str) -> Option<String> {
+    match url_scheme(url)?.to_lowercase().as_str() {
+        "http" | "https" => None,
+        "mailto" => Some("mail".to_string()),
+        s => Some(s.to_string())
+    }
+}
+
+// lowercase the scheme and host of an absolute URL, leaving the rest alone;
+// only enough of RFC 3986 is parsed to find where the authority ends
+fn normalize_url(url: &str) -> String {
+    let scheme = match url_scheme(url) {
+        Some(s) => s,
+        None => return url.to_string()
+    };
+
+    let rest = &url[scheme.len() + 1..];
+    let after_slashes = match rest.strip_prefix("//") {
+        Some(r) => r,
+        None => return format!("{}:{}", scheme.to_lowercase(), rest)
+    };
+
+    let end = after_slashes.find(['/', '?', '#']).unwrap_or(after_slashes.len());
+    let (authority, tail) = after_slashes.split_at(end);
+
+    // userinfo is case-sensitive, so only the part after any '@' is touched
+    let authority = match authority.rfind('@') {
+        Some(at) => format!("{}{}", &authority[..=at],
+                            authority[at + 1..].to_lowercase()),
+        None => authority.to_lowercase()
+    };
+
+    format!("{}://{}{}", scheme.to_lowercase(), authority, tail)
+}
+
+// the scheme of an absolute URL; relative URLs have none
+fn url_scheme(url: &str) -> Option<&str> {
+    let scheme = &url[..url.find(':')?];
+    let valid = scheme.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+
+    if valid { Some(scheme) } else { None }
+}
+
+fn heading_chars(h: &HeadingLevel) -> String {
+    use HeadingLevel::*;
+
+    let s = match h {
+        H1 => "#",
+        H2 => "##",
+        H3 => "###"
+    };
+
+    s.to_string()
+}
+
+// with --numbered-links the list is numbered from first. A link with no
+// other link next to it is a Links block of its own, which --smart-links
+// makes a paragraph rather than a list of one
+fn string_of_links(ll: Vec<Link>, first: usize, opts: &Options) -> String {
+    if ll.is_empty() {
+        return String::from("");
+    }
+    if opts.smart_links && ll.len() == 1 {
+        return format!("{}\n", escape_literal(string_of_link(&ll[0], "", opts).trim_start()));
+    }
+
+    let links: Vec<String> = ll.iter().enumerate()
+        .map(|(i, l)| match opts.numbered_links {
+            Some(_) => string_of_link(l, &format!("{}.", first + i), opts),
+            None => string_of_link(l, "*", opts)
+        }).collect();
+
+    format!("{}\n", links.join(""))
+}
+
+// the URL is everything from the first non-whitespace after "=>" to the
+// next whitespace, as URLs can't contain spaces; the rest, trimmed, is the
+// label
+fn link_of_line(line: String) -> Line {
+    use Line::*;
+
+    let rest = line[2..].trim();
+    match rest.split_once(char::is_whitespace) {
+        _ if rest.is_empty() => MalformedL(Malformed::MLink, line.clone()),
+        Some((url, tag)) => LinkL(Link(url.to_string(), Some(tag.trim().to_string()))),
+        None => LinkL(Link(rest.to_string(), None))
+    }
+}
+
+// text to be read as it stands, where a leading "#" would make a heading,
+// ">" a quotation, "-", "+", "*" or "1." and the like a list item, and a
+// line of "-", "*" or "_" a thematic break
+fn escape_literal(s: &str) -> String {
+    let spaced = |i: usize| s[i..].chars().next().is_none_or(char::is_whitespace);
+    let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let marks: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let rule = marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_')
+        && marks.iter().all(|&c| c == marks[0]);
+
+    match s.chars().next() {
+        Some('#' | '>') => format!("\\{}", s),
+        Some('-' | '+' | '*') if spaced(1) => format!("\\{}", s),
+        _ if rule => format!("\\{}", s),
+        // CommonMark allows at most nine digits before the "." or ")"
+        _ if (1..=9).contains(&digits) && s[digits..].starts_with(['.', ')'])
+            && spaced(digits + 1) => format!("{}\\{}", &s[..digits], &s[digits..]),
+        _ => s.to_string()
+    }
+}
+
+fn trim(s: String) -> String { s.trim().to_string() }
+
+// whatever follows the hashes is literal heading text and is never parsed
+// again, so "# => x" is a heading reading "=> x", not a link. The text is
+// trimmed of whitespace of every kind, tabs and stray CRs included, and
+// if that leaves nothing, as in "#  ", the heading is malformed, as "# " is
+fn make_heading(s: String, level: HeadingLevel, offset: usize) -> Line {
+    let text = s[offset..].trim();
+    if text.is_empty() {
+        return Line::MalformedL(Malformed::MHeading, s);
+    }
+    Line::HeadingL(Heading(level, text.to_string()))
+}
+
+/*
+
+Everything below this point is a five-stage pipeline.
+
+  read_lines:          get the input stream line by line as strings
+  gather_preformatted: tag the strings if they're in a preformatted block
+  decode_lines:        turn the strings into Lines
+  blocks_of_lines:     turn the Lines into Blocks
+  consume_blocks:      write the Blocks to the output
+
+Each stage but the last runs in its own thread. Errors travel down the
+pipeline in place of the values, so the last stage reports the first
+one to occur.
+
+*/
+
+// a later stage that has hung up has an error of its own to report,
+// so there is nothing left to do but stop
+macro_rules! send_or_stop {
+    ($tx:expr, $x:expr) => {
+        if $tx.send($x).is_err() { return; }
+    };
+}
+
+// unwrap a value from an earlier stage, passing any error on instead
+macro_rules! or_forward {
+    ($tx:expr, $r:expr) => {
+        match $r {
+            Ok(x) => x,
+            Err(e) => { let _ = $tx.send(Err(e)); return; }
+        }
+    };
+}
+
+const EMBED_OPEN: &str = "<!-- gemtext -->";
+const EMBED_CLOSE: &str = "<!-- /gemtext -->";
+
+// the bool says whether the line is to be passed through untouched, which
+// is only ever the case outside the markers in --embedded mode
+// the input is split at "\n" by hand, rather than by lines(), so that the
+// length of each line as it was in the input is known
+fn read_lines(input: Box<dyn BufRead + Send>,
+              tx: Sender<Result<(bool, NumString), Error>>,
+              opts: Options) {
+    thread::spawn(move || {
+        let encoding = opts.encoding;
+        let lines = input.split(b'\n').map(|l| l.and_then(|mut l| {
+            let len = l.len() + 1;
+            // as lines() does, accept CRLF line endings too
+            if l.last() == Some(&b'\r') {
+                l.pop();
+            }
+            let s = match encoding {
+                Encoding::Utf8 => String::from_utf8(l).map_err(|_| io::Error::new(
+                    io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?,
+                Encoding::Cp1252 => string_of_cp1252(&l)
+            };
+            Ok((s, len))
+        }));
+
+        let mut in_gemtext = !opts.embedded;
+        let mut opened_at = 0;
+        let mut offset = 0;
+
+        for (i, line) in lines.enumerate() {
+            let lineno = i + 1;
+            let (l, len) = or_forward!(tx, line.map_err(Error::from));
+            let start = offset;
+            offset += len;
+
+            // on stderr, so as to keep out of the output, and only every
+            // so many lines, so as not to slow down a big conversion
+            if let Some(every) = opts.progress {
+                if lineno % every == 0 {
+                    diagnostic(&opts, format!("progress: {} lines read", lineno));
+                }
+            }
+
+            if opts.embedded {
+                let problem = match (l.trim(), in_gemtext) {
+                    (EMBED_OPEN, false) => { in_gemtext = true; opened_at = lineno; continue },
+                    (EMBED_CLOSE, true) => { in_gemtext = false; continue },
+                    (EMBED_OPEN, true)  => Some("gemtext marker nested inside another"),
+                    (EMBED_CLOSE, false) => Some("closing gemtext marker without an opening one"),
+                    _ => None
+                };
+                if let Some(problem) = problem {
+                    let _ = tx.send(Err(Error::Embedding { line: lineno, problem }));
+                    return;
+                }
+            }
+
+            send_or_stop!(tx, Ok((!in_gemtext, NumString(l, lineno, start))));
+        }
+
+        if opts.embedded && in_gemtext {
+            let problem = "gemtext marker never closed";
+            let _ = tx.send(Err(Error::Embedding { line: opened_at, problem }));
+        }
+    });
+}
+
+// Windows-1252 is Latin-1 except for 0x80 to 0x9f, where it has the curly
+// quotes, dashes and so on; the five bytes it leaves undefined there map
+// to the C1 controls, as in the WHATWG encoding standard
+fn string_of_cp1252(bytes: &[u8]) -> String {
+    const HIGH: [char; 32] = [
+        '\u{20ac}', '\u{81}',   '\u{201a}', '\u{192}',  '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+        '\u{2c6}',  '\u{2030}', '\u{160}',  '\u{2039}', '\u{152}',  '\u{8d}',   '\u{17d}',  '\u{8f}',
+        '\u{90}',   '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+        '\u{2dc}',  '\u{2122}', '\u{161}',  '\u{203a}', '\u{153}',  '\u{9d}',   '\u{17e}',  '\u{178}'
+    ];
+
+    bytes.iter().map(|&b| match b {
+        0x80..=0x9f => HIGH[(b - 0x80) as usize],
+        _ => b as char
+    }).collect()
+}
+
+// annotate lines with whether they occur within preformatted blocks
+// mark the lines beginning with "```", or whatever --source-fence says, as
+// opening or closing toggles
+fn gather_preformatted(rx: Receiver<Result<(bool, NumString), Error>>,
+                       tx: Sender<Result<(PrefTag, NumString), Error>>,
+                       opts: Options) {
+    thread::spawn(move || {
+        use PrefTag::*;
+        let mut pref = false;
+        let fence = source_fence(&opts);
+        // with --reset-pre-on-blank, blank lines in a preformatted block are
+        // held back until it's clear whether the block carries on after them
+        let mut blanks: Vec<NumString> = vec![];
+        let mut open_lineno = 0;
+
+        for i in rx {
+            let (raw, i) = or_forward!(tx, i);
+            if raw {
+                for b in blanks.drain(..) {
+                    send_or_stop!(tx, Ok((Pre, b)));
+                }
+                // passing out of the gemtext ends any preformatted block
+                pref = false;
+                send_or_stop!(tx, Ok((Raw, i)));
+                continue;
+            }
+
+            // This is only a guess at where a missing closing toggle should
+            // have been: a run of blank lines can perfectly well belong in
+            // a preformatted block, and a block which lacks its toggle but
+            // has no such run still swallows the rest of the input
+            if let (true, Some(n)) = (pref, opts.reset_pre_on_blank) {
+                if i.0.trim().is_empty() {
+                    blanks.push(i);
+                    if blanks.len() >= n {
+                        warn(&opts, format!("assuming the preformatted block opened at line {} \
+                                             ends at line {}", open_lineno, blanks[0].1));
+                        pref = false;
+                        let first = blanks.remove(0);
+                        send_or_stop!(tx, Ok((Close, NumString(String::new(), first.1, first.2))));
+                        for b in blanks.drain(..) {
+                            send_or_stop!(tx, Ok((Text, b)));
+                        }
+                    }
+                    continue;
+                }
+                for b in blanks.drain(..) {
+                    send_or_stop!(tx, Ok((Pre, b)));
+                }
+            }
+
+            let line = if opts.lenient_headings { i.0.trim_start() } else { &i.0 };
+            // the spec has any toggle line close the block, leaving no way
+            // to show a line starting with backticks; --literal-fences
+            // takes one with text after the backticks to be such a line
+            let literal = opts.literal_fences && fence_text(line, &opts).is_some();
+            let tag = match (line.starts_with(fence), pref) {
+                (true, false)  => Open,
+                (true, true) if literal => Pre,
+                (true, true)   => Close,
+                (false, false) => Text,
+                (false, true)  => Pre
+            };
+
+            if let Open | Close = tag {
+                pref = !pref;
+                open_lineno = i.1;
+            }
+            send_or_stop!(tx, Ok((tag, i)));
+        }
+
+        for b in blanks {
+            send_or_stop!(tx, Ok((Pre, b)));
+        }
+    });
+}
+
+fn source_fence(opts: &Options) -> &str {
+    opts.source_fence.as_deref().unwrap_or("```")
+}
+
+// the text after a toggle's backticks, if there is any
+fn fence_text(s: &str, opts: &Options) -> Option<String> {
+    let t = s.trim_start().get(source_fence(opts).len()..).unwrap_or("").trim();
+    if t.is_empty() { None } else { Some(t.to_string()) }
+}
+
+// convert the incoming strings to Lines
+fn decode_lines(rx: Receiver<Result<(PrefTag, NumString), Error>>,
+                tx: Sender<Result<NumLine, Error>>,
+                opts: Options) {
+    thread::spawn(move || {
+        use Line::{PreformattedL, CommentL, RawL};
+        let mut pref_acc: Vec<String> = vec![];
+        let mut alt: Option<String> = None;
+        // preformatted blocks are numbered by their opening toggle, which
+        // keeps the number meaningful when the input ends mid-block
+        let mut open_lineno = 0;
+        let mut open_offset = 0;
+        let mut open = false;
+
+        for i in rx {
+            let (tag, NumString(s, lineno, offset)) = or_forward!(tx, i);
+            match tag {
+                PrefTag::Open => {
+                    alt = fence_text(&s, &opts);
+                    open_lineno = lineno;
+                    open_offset = offset;
+                    open = true;
+                },
+                PrefTag::Pre => pref_acc.push(s),
+                // the block goes out at its closing toggle, ahead of whatever
+                // follows, so two blocks with a line between never merge
+                PrefTag::Close => {
+                    open = false;
+                    if !pref_acc.is_empty() {
+                        send_or_stop!(tx, Ok(NumLine(PreformattedL(alt.take(),
+                                                                   pref_acc.clone()),
+                                                     open_lineno, open_offset)));
+                        pref_acc.clear();
+                    }
+                    // the alt text belongs to this block alone, even an
+                    // empty one, and mustn't carry over to the next
+                    alt = None;
+
+                    // the spec says to ignore this text, but it is kept on request
+                    if let (true, Some(t)) = (opts.keep_close_alt, fence_text(&s, &opts)) {
+                        send_or_stop!(tx, Ok(NumLine(CommentL(t), lineno, offset)));
+                    }
+                },
+                PrefTag::Text => {
+                    let s = match opts.sanitize_control {
+                        Some(how) => sanitize_control(&s, how),
+                        None => s
+                    };
+                    send_or_stop!(tx, Ok(NumLine(line_of_string(s, &opts), lineno, offset)))
+                },
+                PrefTag::Raw => {
+                    if open {
+                        warn_unclosed(open_lineno, &opts);
+                        open = false;
+                    }
+                    if !pref_acc.is_empty() {
+                        send_or_stop!(tx, Ok(NumLine(PreformattedL(alt.take(),
+                                                                   pref_acc.clone()),
+                                                     open_lineno, open_offset)));
+                        pref_acc.clear();
+                    }
+                    alt = None;
+                    send_or_stop!(tx, Ok(NumLine(RawL(s), lineno, offset)));
+                }
+            }
+        }
+
+        // a truncated file often ends mid-block; what there is of the block
+        // is still fenced off properly
+        if open {
+            warn_unclosed(open_lineno, &opts);
+        }
+        if !pref_acc.is_empty() {
+            send_or_stop!(tx, Ok(NumLine(PreformattedL(alt, pref_acc), open_lineno, open_offset)));
+        }
+    });
+}
+
+fn warn_unclosed(lineno: usize, opts: &Options) {
+    warn(opts, format!("preformatted block opened at line {} is never closed", lineno));
+}
+
+// aggregate Lines into Blocks
+// each Block is numbered by its first line. Blocks go out in the order their
+// lines came in, and links within a LinksB keep their order too: nothing
+// downstream sorts them, and the output relies on that
+fn blocks_of_lines(rx: Receiver<Result<NumLine, Error>>,
+                   tx: Sender<Result<NumBlock, Error>>,
+                   opts: Options) {
+    thread::spawn(move || {
+        use Block::*;
+        use Line::*;
+
+        let mut links: Vec<Link> = vec![];
+        let mut links_at = (0, 0);
+        let mut quote: Vec<String> = vec![];
+        let mut quote_at = (0, 0);
+        let mut list: Vec<String> = vec![];
+        let mut list_at = (0, 0);
+        let mut headings_seen: HashMap<String, usize> = HashMap::new();
+        let mut labels_seen: HashMap<String, (String, usize)> = HashMap::new();
+
+        for i in rx {
+            let NumLine(line, lineno, offset) = or_forward!(tx, i);
+            let flush_list = !matches!(line, ListL(_));
+            let (flush_links, flush_quote, payload) = match line {
+                // "=>url", with no space, is malformed but hardly empty
+                MalformedL(Malformed::MLink, text)
+                    if opts.preserve_empty_links && text[2..].trim().is_empty() =>
+                    (true, true, vec![CommentB(format!("empty link: \"{}\"", text.trim()))]),
+                MalformedL(m, text) if opts.mark_malformed => (true, true, vec![
+                    CommentB(format!("malformed {}", m)),
+                    ParaB(escape_literal(text.trim()))
+                ]),
+                // what was gathered before the error still goes out ahead
+                // of it; at most one of these is ever part way through
+                MalformedL(m, _) => {
+                    if !links.is_empty() {
+                        check_link_run(&links, links_at.0, &opts);
+                        send_or_stop!(tx, Ok(NumBlock(LinksB(links), links_at.0, links_at.1)));
+                    }
+                    if !quote.is_empty() {
+                        send_or_stop!(tx, Ok(NumBlock(QuoteB(quote), quote_at.0, quote_at.1)));
+                    }
+                    if !list.is_empty() {
+                        send_or_stop!(tx, Ok(NumBlock(ListB(list), list_at.0, list_at.1)));
+                    }
+                    let _ = tx.send(Err(Error::Malformed { line: lineno, kind: m }));
+                    return;
+                },
+                LinkL(link)      => {
+                    if opts.lint_links {
+                        lint_link(&mut labels_seen, &link, lineno, &opts);
+                    }
+                    if links.is_empty() {
+                        links_at = (lineno, offset);
+                    }
+                    links.push(link);
+                    (false, true, vec![])
+                },
+                // a blank line ends the quotation; quote lines after it
+                // start another
+                QuoteL(q)        => {
+                    if quote.is_empty() {
+                        quote_at = (lineno, offset);
+                    }
+                    quote.push(q);
+                    (true, false, vec![])
+                },
+                ListL(item)      => {
+                    if list.is_empty() {
+                        list_at = (lineno, offset);
+                    }
+                    list.push(item);
+                    (true, true, vec![])
+                },
+                BlankL           => (true, true, vec![]),
+                ParaL(p)         => (true, true, vec![ParaB(p)]),
+                HeadingL(h)      => {
+                    if opts.warn_dup_headings {
+                        check_dup_heading(&mut headings_seen, &h, lineno, &opts);
+                    }
+                    (true, true, vec![HeadingB(h)])
+                },
+                PreformattedL(a, p) => (true, true, vec![PreformattedB(a, p)]),
+                CommentL(c)      => (true, true, vec![CommentB(c)]),
+                RawL(r)          => (true, true, vec![RawB(r)])
+            };
+
+            // most lines flush the links, but usually there are none, and an
+            // empty LinksB would only be thrown away downstream; likewise
+            // quotations
+            if flush_links && !links.is_empty() {
+                check_link_run(&links, links_at.0, &opts);
+                send_or_stop!(tx, Ok(NumBlock(LinksB(links.clone()), links_at.0, links_at.1)));
+                links.clear();
+            }
+            if flush_quote && !quote.is_empty() {
+                send_or_stop!(tx, Ok(NumBlock(QuoteB(quote.clone()), quote_at.0, quote_at.1)));
+                quote.clear();
+            }
+            if flush_list && !list.is_empty() {
+                send_or_stop!(tx, Ok(NumBlock(ListB(list.clone()), list_at.0, list_at.1)));
+                list.clear();
+            }
+
+            for p in payload {
+                send_or_stop!(tx, Ok(NumBlock(p, lineno, offset)));
+            }
+        }
+
+        if !links.is_empty() {
+            check_link_run(&links, links_at.0, &opts);
+            send_or_stop!(tx, Ok(NumBlock(LinksB(links), links_at.0, links_at.1)));
+        }
+        if !quote.is_empty() {
+            send_or_stop!(tx, Ok(NumBlock(QuoteB(quote), quote_at.0, quote_at.1)));
+        }
+        if !list.is_empty() {
+            send_or_stop!(tx, Ok(NumBlock(ListB(list), list_at.0, list_at.1)));
+        }
+    });
+}
+
+// every non-fatal diagnostic comes through here, so that --quiet can
+// silence them all; fatal errors are returned as Errors instead
+fn diagnostic(opts: &Options, msg: String) {
+    if !opts.quiet {
+        eprintln!("{}", msg);
+    }
+}
+
+// public so that the commandline tool's own warnings go the same way
+pub fn warn(opts: &Options, msg: String) {
+    diagnostic(opts, format!("warning: {}", msg));
+}
+
+// warn if this heading's text has been seen before
+fn check_dup_heading(seen: &mut HashMap<String, usize>, h: &Heading,
+                     lineno: usize, opts: &Options) {
+    let Heading(_, text) = h;
+    let key = if opts.exact_dup_headings { text.clone() } else { text.to_lowercase() };
+
+    match seen.get(&key) {
+        Some(first) => warn(opts, format!("duplicate heading \"{}\" at line {} \
+                                           (first seen at line {})", text, lineno, first)),
+        None => { seen.insert(key, lineno); }
+    }
+}
+
+fn check_link_run(links: &[Link], lineno: usize, opts: &Options) {
+    if let Some(n) = opts.warn_link_run {
+        if links.len() > n {
+            warn(opts, format!("{} links in a row from line {}", links.len(), lineno));
+        }
+    }
+}
+
+// seen holds the first label given to each URL, and where
+fn lint_link(seen: &mut HashMap<String, (String, usize)>, l: &Link,
+             lineno: usize, opts: &Options) {
+    let Link(url, tag) = l;
+    if tag.as_deref() == Some(url.as_str()) {
+        warn(opts, format!("link at line {} has its URL as its label", lineno));
+    }
+
+    let Some(label) = tag else { return };
+    match seen.get(url) {
+        Some((first, n)) if first != label =>
+            warn(opts, format!("link to {} at line {} is labelled \"{}\", but \"{}\" \
+                                at line {}", url, lineno, label, first, n)),
+        Some(_) => (),
+        None => { seen.insert(url.clone(), (label.clone(), lineno)); }
+    }
+}
+
+// write the Blocks out, noting what goes past, between any header and
+// footer. The frontmatter and table of contents are drawn from the whole
+// document, so when there are any the Blocks are gathered up before
+// anything is written. Nothing here knows about stdout: main hands in the
+// locked stdout, split_on_h1 a Vec<u8>, and convert_reader_to_writer
+// whatever its caller likes
+fn consume_blocks<'a, I>(rx: I, out: &mut dyn Write, opts: &Options,
+                         source: Option<&str>) -> Result<Summary, Error>
+where I: IntoIterator<Item = Result<NumBlock, Error>>, I::IntoIter: 'a {
+    let canonical;
+    let opts = if opts.canonical {
+        canonical = canonical_options(opts);
+        &canonical
+    } else {
+        opts
+    };
+    // everything is rendered with "\n", and translated on the way out
+    let mut crlf;
+    let mut trim;
+    let out: &mut dyn Write = match opts.eol {
+        _ if opts.canonical => { trim = TrimWriter { inner: out, spaces: vec![] }; &mut trim },
+        Eol::Crlf => { crlf = CrlfWriter { inner: out, last: 0 }; &mut crlf },
+        Eol::Lf => out
+    };
+    let mut summary = Summary::default();
+    let mut pending: Option<(BlockKind, usize, usize)> = None;
+    let mut seeking_lead = true;
+    let mut heading_numbers = [0; 3];
+    let mut toc = String::new();
+    let mut written = 0;
+    let mut links_numbered = 0;
+    let mut prev_heading = None;
+
+    let blocks: Box<dyn Iterator<Item = Result<NumBlock, Error>> + 'a> =
+        if wants_frontmatter(opts) || opts.toc {
+            let all = rx.into_iter().collect::<Result<Vec<_>, _>>()?;
+            out.write_all(string_of_frontmatter(&frontmatter_of_blocks(&all, opts)).as_bytes())?;
+            if opts.toc {
+                toc = string_of_toc(&all, opts);
+            }
+            Box::new(all.into_iter().map(Ok))
+        } else {
+            Box::new(rx.into_iter())
+        };
+    // frontmatter has to come first, but otherwise the provenance does
+    if let Some(p) = &opts.provenance {
+        out.write_all(format!("{}\n", html_comment(p)).as_bytes())?;
+    }
+    if let Some(h) = &opts.header {
+        out.write_all(h.as_bytes())?;
+    }
+    out.write_all(toc.as_bytes())?;
+
+    for i in blocks {
+        // stopping early drops the channel, and the earlier stages stop
+        // as soon as they find nobody is listening
+        if opts.max_blocks.is_some_and(|m| written >= m) {
+            break;
+        }
+        let NumBlock(b, lineno, _) = i?;
+        let subtitle = is_subtitle(&mut prev_heading, &b, lineno, opts);
+        let lead = is_lead(&mut seeking_lead, &b);
+        match &b {
+            Block::HeadingB(Heading(HeadingLevel::H1, t)) if summary.title.is_none() =>
+                summary.title = Some(t.clone()),
+            Block::LinksB(ll) => summary.link_count += ll.len(),
+            _ => ()
+        }
+        let b = match b {
+            Block::HeadingB(h) if opts.number_headings && !subtitle =>
+                Block::HeadingB(number_heading(&mut heading_numbers, h)),
+            b => b
+        };
+
+        if let Some(kinds) = &opts.only {
+            if !kinds.contains(&kind_of_block(&b)) {
+                continue;
+            }
+        }
+
+        // a whitespace-only line comes through as an empty paragraph, which
+        // is dropped rather than let it double up the blank lines
+        let s = match &b {
+            Block::ParaB(p) if lead && opts.lead_paragraph == LeadParagraph::Class =>
+                format!("<p class=\"lead\">{}</p>\n\n", html_escape(p)),
+            Block::HeadingB(Heading(_, t)) if subtitle =>
+                format!("*{}*\n\n", t.replace('*', "\\*")),
+            Block::LinksB(ll) if opts.numbered_links == Some(LinkNumbering::Continuous) => {
+                links_numbered += ll.len();
+                string_of_links(ll.to_vec(), links_numbered - ll.len() + 1, opts)
+            },
+            _ => string_of_block(&b, opts)
+        };
+        // indented code straight after a list would be read as more of its
+        // last item, so an empty comment comes between, to end the list
+        let after_list = matches!(pending, Some((BlockKind::Links | BlockKind::List, _, _)));
+        let s = if opts.indent_code && after_list && s.starts_with([' ', '\t']) {
+            format!("<!-- -->\n\n{}", s)
+        } else {
+            s
+        };
+        let para = matches!(b, Block::ParaB(_));
+        if s.is_empty() || ((opts.compact || para) && s.trim().is_empty()) {
+            continue;
+        }
+        summary.wrote_any |= !s.trim().is_empty();
+        if opts.validate {
+            validate_block(&b, &s, lineno, opts);
+        }
+
+        // each block's trailing blank lines are held back until the next
+        // block arrives, so the spacing between them can be adjusted
+        let kind = kind_of_block(&b);
+        let body = s.trim_end_matches('\n');
+        if let Some((prev, n, prev_lineno)) = pending {
+            let adjacent = lineno == prev_lineno + 1;
+            out.write_all("\n".repeat(blank_lines(prev, kind, n, adjacent, opts)).as_bytes())?;
+        }
+        out.write_all(body.as_bytes())?;
+        out.write_all(b"\n")?;
+        if opts.stream {
+            out.flush()?;
+        }
+        pending = Some((kind, s.len() - body.len() - 1, lineno));
+        written += 1;
+    }
+
+    // canonical output ends with the last block's newline, unless there is
+    // a footer or the source to come
+    let last = opts.footer.is_none() && source.is_none();
+    if let (Some((_, n, _)), false) = (pending, opts.canonical && last) {
+        out.write_all("\n".repeat(n).as_bytes())?;
+    }
+    if let Some(f) = &opts.footer {
+        out.write_all(f.as_bytes())?;
+    }
+    if let Some(src) = source {
+        out.write_all(string_of_source(src).as_bytes())?;
+    }
+    out.flush()?;
+
+    Ok(summary)
+}
+
+// the gemtext, for --embed-source, in a comment from which it can be got
+// back: "&" is written "&amp;", so that "-->" and "--!>", which would end
+// the comment, can be written "--&gt;" and "--!&gt;". Undoing those in the
+// opposite order gives the source, with a final newline if it lacked one
+fn string_of_source(src: &str) -> String {
+    let escaped = src.replace('&', "&amp;").replace("-->", "--&gt;").replace("--!>", "--!&gt;");
+    let nl = if escaped.ends_with('\n') || escaped.is_empty() { "" } else { "\n" };
+    format!("<!-- GEMTEXT SOURCE\n{}{}-->\n", escaped, nl)
+}
+
+// prefix a heading with its place in the document, "1.2" for the second
+// H2 under the first H1, say; each heading restarts the numbering of
+// those below it, and a level skipped over counts as 0
+fn number_heading(numbers: &mut [usize; 3], h: Heading) -> Heading {
+    let Heading(level, text) = h;
+    let depth = heading_depth(&level);
+
+    numbers[depth] += 1;
+    for n in &mut numbers[depth + 1..] {
+        *n = 0;
+    }
+    let prefix: Vec<String> = numbers[..=depth].iter().map(|n| n.to_string()).collect();
+
+    Heading(level, format!("{} {}", prefix.join("."), text))
+}
+
+// H1 is at depth 0
+fn heading_depth(h: &HeadingLevel) -> usize {
+    match h {
+        HeadingLevel::H1 => 0,
+        HeadingLevel::H2 => 1,
+        HeadingLevel::H3 => 2
+    }
+}
+
+// experimental: with --subtitle, a heading on the line straight after a
+// higher level one is taken for its subtitle, and rendered in italics
+// rather than as a heading. prev notes the last heading, if that was the
+// last Block; a subtitle can't have a subtitle of its own
+fn is_subtitle(prev: &mut Option<(HeadingLevel, usize)>, b: &Block, lineno: usize,
+               opts: &Options) -> bool {
+    let Block::HeadingB(Heading(level, _)) = b else {
+        *prev = None;
+        return false;
+    };
+    let subtitle = opts.subtitle && prev.is_some_and(|(l, n)| {
+        n + 1 == lineno && heading_depth(level) > heading_depth(&l)
+    });
+    *prev = if subtitle { None } else { Some((*level, lineno)) };
+    subtitle
+}
+
+// a nested list of links to the headings, indented relative to the
+// shallowest heading listed
+fn string_of_toc(blocks: &[NumBlock], opts: &Options) -> String {
+    let mut numbers = [0; 3];
+    let mut anchors = HashMap::new();
+    let mut entries = vec![];
+    let mut prev_heading = None;
+
+    for NumBlock(b, lineno, _) in blocks {
+        if is_subtitle(&mut prev_heading, b, *lineno, opts) {
+            continue;
+        }
+        let Block::HeadingB(h) = b else { continue };
+        let level = h.0;
+        let Heading(_, text) = if opts.number_headings {
+            number_heading(&mut numbers, h.clone())
+        } else {
+            h.clone()
+        };
+        // every heading takes an anchor, listed or not, or the numbering
+        // of anchors for repeated headings would be thrown out
+        let anchor = anchor_of_heading(&mut anchors, &text);
+        if heading_depth(&level) < opts.toc_depth.unwrap_or(3) {
+            entries.push((heading_depth(&level), text, anchor));
+        }
+    }
+
+    let Some(top) = entries.iter().map(|(d, _, _)| *d).min() else { return String::new() };
+    let indent = opts.toc_indent.unwrap_or(2);
+    let items: String = entries.iter().map(|(depth, text, anchor)| {
+        format!("{}* [{}](#{})\n", " ".repeat((depth - top) * indent), text, anchor)
+    }).collect();
+
+    format!("{}\n", items)
+}
+
+// the id a heading is given by GitHub and the like: lowercased, spaces
+// hyphenated and other punctuation dropped, with "-1", "-2", etc added
+// for the second and later headings giving the same id
+fn anchor_of_heading(seen: &mut HashMap<String, usize>, text: &str) -> String {
+    let base: String = text.trim().to_lowercase().chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect();
+
+    let n = seen.entry(base.clone()).or_insert(0);
+    *n += 1;
+    if *n == 1 { base } else { format!("{}-{}", base, *n - 1) }
+}
+
+// the lead is the first paragraph, provided only headings and comments
+// come before it; seeking starts out true, and is cleared once the lead
+// has been found or ruled out
+fn is_lead(seeking: &mut bool, b: &Block) -> bool {
+    if !*seeking {
+        return false;
+    }
+    match b {
+        Block::HeadingB(_) | Block::CommentB(_) => false,
+        Block::ParaB(p) if p.trim().is_empty() => false,
+        Block::ParaB(_) => { *seeking = false; true },
+        _ => { *seeking = false; false }
+    }
+}
+
+fn wants_frontmatter(opts: &Options) -> bool {
+    opts.lead_paragraph == LeadParagraph::Frontmatter || opts.detect_lang ||
+        opts.slug.is_some() || opts.links_frontmatter.is_some()
+}
+
+// the frontmatter's fields, in the order they're written
+fn frontmatter_of_blocks(blocks: &[NumBlock],
+                         opts: &Options) -> Vec<(&'static str, String)> {
+    let mut fields = vec![];
+    if let Some(slug) = &opts.slug {
+        fields.push(("slug", yaml_string(slug)));
+    }
+    let mut seeking_lead = opts.lead_paragraph == LeadParagraph::Frontmatter;
+
+    for NumBlock(b, _, _) in blocks {
+        let lead = is_lead(&mut seeking_lead, b);
+        if let (true, Block::ParaB(p)) = (lead, b) {
+            fields.push(("description", yaml_string(p)));
+        }
+    }
+
+    if let (true, Some(lang)) = (opts.detect_lang, detect_lang(blocks)) {
+        fields.push(("lang", yaml_string(&lang)));
+    }
+    if let Some(style) = opts.links_frontmatter {
+        fields.push(("links", yaml_of_links(blocks, style, opts)));
+    }
+
+    fields
+}
+
+// every link's URL as a YAML sequence, or with --links-frontmatter-labels a
+// sequence of mappings which give the label too; with --dedup-links only
+// the first link to each URL is listed
+fn yaml_of_links(blocks: &[NumBlock], style: LinksFrontmatter, opts: &Options) -> String {
+    let mut seen = HashSet::new();
+    let mut acc = String::new();
+
+    for Link(url, label) in blocks.iter().flat_map(|NumBlock(b, _, _)| match b {
+        Block::LinksB(ll) => ll.as_slice(),
+        _ => &[]
+    }) {
+        let url = url_of_link(url.clone(), opts);
+        if opts.dedup_links && !seen.insert(url.clone()) {
+            continue;
+        }
+        match (style, label) {
+            (LinksFrontmatter::Labelled, Some(l)) => acc.push_str(
+                &format!("\n  - url: {}\n    label: {}", yaml_string(&url), yaml_string(l))),
+            (LinksFrontmatter::Labelled, None) =>
+                acc.push_str(&format!("\n  - url: {}", yaml_string(&url))),
+            (LinksFrontmatter::Urls, _) => acc.push_str(&format!("\n  - {}", yaml_string(&url)))
+        }
+    }
+
+    if acc.is_empty() { String::from("[]") } else { acc }
+}
+
+// a guess at the document's language, as a BCP 47 tag, from the script
+// most of its headings and paragraphs are written in. Most scripts are
+// shared between languages, so all that can be said is e.g. "und-Latn",
+// undetermined language in Latin script; kana and hangul are exceptions
+fn detect_lang(blocks: &[NumBlock]) -> Option<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+
+    for NumBlock(b, _, _) in blocks {
+        let text = match b {
+            Block::HeadingB(Heading(_, t)) | Block::ParaB(t) => t,
+            _ => continue
+        };
+        for script in text.chars().filter_map(script_of_char) {
+            *counts.entry(script).or_default() += 1;
+        }
+    }
+
+    // Japanese mixes kana with kanji, which are Han characters
+    if counts.contains_key("Kana") {
+        let han = counts.remove("Hani").unwrap_or(0);
+        *counts.entry("Kana").or_default() += han;
+    }
+
+    let (script, _) = counts.into_iter()
+        .max_by(|(s1, n1), (s2, n2)| n1.cmp(n2).then(s2.cmp(s1)))?;
+    Some(match script {
+        "Kana" => "ja".to_string(),
+        "Hang" => "ko".to_string(),
+        s => format!("und-{}", s)
+    })
+}
+
+// the ISO 15924 code for the script of a letter, for the commoner scripts
+fn script_of_char(c: char) -> Option<&'static str> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{c0}'..='\u{24f}' | '\u{1e00}'..='\u{1eff}'
+            if c.is_alphabetic() => Some("Latn"),
+        '\u{370}'..='\u{3ff}' | '\u{1f00}'..='\u{1fff}' => Some("Grek"),
+        '\u{400}'..='\u{52f}' => Some("Cyrl"),
+        '\u{590}'..='\u{5ff}' => Some("Hebr"),
+        '\u{600}'..='\u{6ff}' | '\u{750}'..='\u{77f}' => Some("Arab"),
+        '\u{900}'..='\u{97f}' => Some("Deva"),
+        '\u{e00}'..='\u{e7f}' => Some("Thai"),
+        '\u{1100}'..='\u{11ff}' | '\u{3130}'..='\u{318f}' |
+        '\u{ac00}'..='\u{d7af}' => Some("Hang"),
+        '\u{3040}'..='\u{30ff}' => Some("Kana"),
+        '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' => Some("Hani"),
+        _ => None
+    }
+}
+
+// lowercase letters and digits, with a hyphen for each run of anything else
+pub fn slugify(s: &str) -> String {
+    let mut acc = String::new();
+    for c in s.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            acc.push(c);
+        } else if !acc.is_empty() && !acc.ends_with('-') {
+            acc.push('-');
+        }
+    }
+
+    acc.trim_end_matches('-').to_string()
+}
+
+fn string_of_frontmatter(fields: &[(&str, String)]) -> String {
+    if fields.is_empty() {
+        return String::new();
+    }
+    // a value on lines of its own, such as a sequence, starts with "\n"
+    let body: String = fields.iter()
+        .map(|(k, v)| if v.starts_with('\n') {
+            format!("{}:{}\n", k, v)
+        } else {
+            format!("{}: {}\n", k, v)
+        })
+        .collect();
+    format!("---\n{}---\n\n", body)
+}
+
+// a YAML double-quoted scalar
+fn yaml_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// turns "\n" into "\r\n", leaving alone any "\r\n" already there, as in
+// a header file written on Windows
+struct CrlfWriter<'a> {
+    inner: &'a mut dyn Write,
+    last: u8
+}
+
+impl Write for CrlfWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut acc = Vec::with_capacity(buf.len() + buf.len() / 16);
+        for &b in buf {
+            if b == b'\n' && self.last != b'\r' {
+                acc.push(b'\r');
+            }
+            acc.push(b);
+            self.last = b;
+        }
+        self.inner.write_all(&acc)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// what --canonical guarantees, for output to be compared from one version
+// to the next: one blank line between blocks, raw lines included, LF line
+// endings, no trailing whitespace on any line, preformatted or not, and a
+// single newline at the end. The rest is done by TrimWriter and
+// consume_blocks
+fn canonical_options(opts: &Options) -> Options {
+    Options {
+        block_spacing: Some(1),
+        eol: Eol::Lf,
+        trim_pre_trailing: true,
+        ..opts.clone()
+    }
+}
+
+// drops spaces and tabs at the ends of lines, holding them back until it
+// sees what follows them; a CR before the LF goes the same way, as from a
+// header file written on Windows, which would otherwise keep its CRLFs
+struct TrimWriter<'a> {
+    inner: &'a mut dyn Write,
+    spaces: Vec<u8>
+}
+
+impl Write for TrimWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut acc = Vec::with_capacity(buf.len());
+        for &b in buf {
+            match b {
+                b' ' | b'\t' | b'\r' => self.spaces.push(b),
+                b'\n' => { self.spaces.clear(); acc.push(b); },
+                _ => { acc.append(&mut self.spaces); acc.push(b); }
+            }
+        }
+        self.inner.write_all(&acc)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// look for signs that a block has come out as broken Markdown; this is
+// heuristic, and only ever warns
+fn validate_block(b: &Block, s: &str, lineno: usize, opts: &Options) {
+    let fences = s.lines()
+        .filter(|l| l.trim_start().starts_with("```") || l.trim_start().starts_with("~~~"))
+        .count();
+    let expected = if let Block::PreformattedB(..) = b { 2 } else { 0 };
+
+    if fences % 2 != 0 {
+        warn(opts, format!("unbalanced code fence in block at line {}", lineno));
+    } else if fences > expected {
+        warn(opts, format!("code fence inside block at line {} may end it early", lineno));
+    }
+
+    if let Block::PreformattedB(..) | Block::RawB(_) = b {
+        return;
+    }
+
+    for l in s.lines() {
+        if l.matches('[').count() != l.matches(']').count() {
+            warn(opts, format!("unbalanced brackets in block at line {}", lineno));
+            return;
+        }
+    }
+
+    if let Block::LinksB(ll) = b {
+        for l in ll {
+            if l.url().contains([' ', '<', '>']) ||
+                l.url().matches('(').count() != l.url().matches(')').count() {
+                warn(opts, format!("URL {} in block at line {} may not survive as a link",
+                                   l.url(), lineno));
+            }
+        }
+    }
+}
+
+// how many blank lines to put between two blocks, the first of which
+// asked to be followed by n; adjacent blocks had no blank line between
+// them in the gemtext, if the first was one line long
+fn blank_lines(prev: BlockKind, next: BlockKind, n: usize, adjacent: bool,
+               opts: &Options) -> usize {
+    use BlockKind::*;
+
+    match (prev, next) {
+        (Heading, Links | List) if opts.compact => 0,
+        (Heading, Links) if adjacent && opts.tight_heading_links => 0,
+        // raw lines belong to the host document, whose spacing is its own,
+        // except that where the gemtext starts or ends there must be at
+        // least one blank line, the markers having been dropped, or the
+        // host's text and the gemtext's would run together
+        (Raw, Raw) if !opts.canonical => n,
+        (Raw, _) | (_, Raw) if !opts.canonical => n.max(1),
+        _ => opts.block_spacing.unwrap_or(1)
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut acc = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => acc.push_str("\\\""),
+            '\\' => acc.push_str("\\\\"),
+            '\n' => acc.push_str("\\n"),
+            '\r' => acc.push_str("\\r"),
+            '\t' => acc.push_str("\\t"),
+            c if (c as u32) < 0x20 => acc.push_str(&format!("\\u{:04x}", c as u32)),
+            c => acc.push(c)
+        }
+    }
+    acc.push('"');
+    acc
+}
+
+// a JSON array of strings
+fn json_strings(ss: &[String]) -> String {
+    let items: Vec<String> = ss.iter().map(|s| json_string(s)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn json_of_option(s: &Option<String>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None => "null".to_string()
+    }
+}
+
+// the name --jsonl and --oneline give each type of Block
+fn type_of_block(b: &Block) -> &'static str {
+    use Block::*;
+
+    match b {
+        PreformattedB(_, _) => "preformatted",
+        ParaB(_)            => "paragraph",
+        LinksB(_)           => "links",
+        HeadingB(_)         => "heading",
+        QuoteB(_)           => "quote",
+        ListB(_)            => "list",
+        CommentB(_)         => "comment",
+        RawB(_)             => "raw"
+    }
+}
+
+// one Block as a JSON object on a single line
+fn json_of_block(b: &Block, lineno: usize, offset: Option<usize>) -> String {
+    use Block::*;
+
+    let fields = match b {
+        PreformattedB(alt, prpr) =>
+            format!("\"alt\": {}, \"lines\": {}", json_of_option(alt), json_strings(prpr)),
+        ParaB(p) => format!("\"text\": {}", json_string(p)),
+        LinksB(ll) => {
+            let links: Vec<String> = ll.iter().map(|Link(url, label)| {
+                format!("{{\"url\": {}, \"label\": {}}}", json_string(url), json_of_option(label))
+            }).collect();
+            format!("\"links\": [{}]", links.join(", "))
+        },
+        HeadingB(Heading(level, t)) =>
+            format!("\"level\": {}, \"text\": {}", heading_depth(level) + 1, json_string(t)),
+        QuoteB(q) => format!("\"lines\": {}", json_strings(q)),
+        ListB(items) => format!("\"items\": {}", json_strings(items)),
+        CommentB(c) => format!("\"text\": {}", json_string(c)),
+        RawB(r) => format!("\"text\": {}", json_string(r))
+    };
+
+    let offset = offset.map(|o| format!("\"byte_offset\": {}, ", o)).unwrap_or_default();
+    format!("{{\"type\": \"{}\", \"line\": {}, {}{}}}\n", type_of_block(b), lineno, offset, fields)
+}
+
+// a terminal stage writing each Block as a line of JSON as it arrives; an
+// error ends the stream with an object of type "error", and is returned
+fn jsonl_blocks(rx: Receiver<Result<NumBlock, Error>>, out: &mut dyn Write,
+                opts: &Options) -> Result<(), Error> {
+    for i in rx {
+        let NumBlock(b, lineno, offset) = match i {
+            Ok(nb) => nb,
+            Err(e) => {
+                let msg = format!("{{\"type\": \"error\", \"message\": {}}}\n",
+                                  json_string(&e.to_string()));
+                out.write_all(msg.as_bytes())?;
+                out.flush()?;
+                return Err(e);
+            }
+        };
+        if let Some(kinds) = &opts.only {
+            if !kinds.contains(&kind_of_block(&b)) {
+                continue;
+            }
+        }
+
+        let offset = if opts.byte_offsets { Some(offset) } else { None };
+        out.write_all(json_of_block(&b, lineno, offset).as_bytes())?;
+        if opts.stream {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+// an HTML attribute's value, in double quotes
+fn html_attr(s: &str) -> String {
+    format!("\"{}\"", html_escape(s).replace('"', "&quot;"))
+}
+
+// one Block as HTML; with --html-source-attrs each element says which line
+// of the gemtext it came from
+fn html_of_block(b: &Block, lineno: usize, opts: &Options) -> String {
+    use Block::*;
+
+    let attrs = if opts.html_source_attrs {
+        format!(" data-gmi-line=\"{}\"", lineno)
+    } else {
+        String::new()
+    };
+    let items = |items: Vec<String>| -> String {
+        items.iter().map(|i| format!("<li>{}</li>\n", i)).collect()
+    };
+
+    match b {
+        PreformattedB(alt, prpr) => {
+            let label = alt.as_ref().map(|a| format!(" aria-label={}", html_attr(a)));
+            format!("<pre{}{}><code>{}\n</code></pre>\n", attrs, label.unwrap_or_default(),
+                    html_escape(&prpr.join("\n")))
+        },
+        ParaB(p) if p.trim().is_empty() => String::new(),
+        ParaB(p) => format!("<p{}>{}</p>\n", attrs, html_escape(p)),
+        LinksB(ll) => {
+            let links = ll.iter().map(|Link(url, label)| {
+                let url = url_of_link(url.clone(), opts);
+                let caption = label.as_ref().filter(|l| !l.trim().is_empty()).unwrap_or(&url);
+                format!("<a href={}>{}</a>", html_attr(&url), html_escape(caption))
+            }).collect();
+            format!("<ul{}>\n{}</ul>\n", attrs, items(links))
+        },
+        HeadingB(Heading(level, t)) => {
+            let n = output_level(level, opts);
+            format!("<h{}{}>{}</h{}>\n", n, attrs, html_escape(t), n)
+        },
+        QuoteB(q) => {
+            let paras: String = q.iter().filter(|l| !l.is_empty())
+                .map(|l| format!("<p>{}</p>\n", html_escape(l))).collect();
+            format!("<blockquote{}>\n{}</blockquote>\n", attrs, paras)
+        },
+        ListB(l) => format!("<ul{}>\n{}</ul>\n", attrs,
+                            items(l.iter().map(|i| html_escape(i)).collect())),
+        CommentB(c) => html_comment(c),
+        RawB(r) => format!("{}\n", r)
+    }
+}
+
+// a terminal stage writing the Blocks as a fragment of HTML, to go in the
+// body of a page, for --html
+fn html_blocks(rx: Receiver<Result<NumBlock, Error>>, out: &mut dyn Write,
+               opts: &Options) -> Result<(), Error> {
+    for i in rx {
+        let NumBlock(b, lineno, _) = i?;
+        if let Some(kinds) = &opts.only {
+            if !kinds.contains(&kind_of_block(&b)) {
+                continue;
+            }
+        }
+
+        out.write_all(html_of_block(&b, lineno, opts).as_bytes())?;
+        if opts.stream {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+// a terminal stage writing each Block's Markdown on a line of its own, after
+// its type and line number, with a visible mark where the Markdown has
+// line breaks; this is for searching with grep, and isn't Markdown itself
+fn oneline_blocks(rx: Receiver<Result<NumBlock, Error>>, out: &mut dyn Write,
+                  opts: &Options) -> Result<(), Error> {
+    for i in rx {
+        let NumBlock(b, lineno, _) = i?;
+        if let Some(kinds) = &opts.only {
+            if !kinds.contains(&kind_of_block(&b)) {
+                continue;
+            }
+        }
+
+        let s = string_of_block(&b, opts);
+        let line = format!("{} {}: {}\n", type_of_block(&b), lineno,
+                           s.trim_end_matches('\n').replace('\n', "\u{23ce}"));
+        out.write_all(line.as_bytes())?;
+        if opts.stream {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+// a terminal stage which just tallies the blocks, for --count-only
+fn count_blocks(rx: Receiver<Result<NumBlock, Error>>) -> Result<Counts, Error> {
+    let mut counts = Counts::default();
+
+    for i in rx {
+        let NumBlock(b, _, _) = i?;
+        // whitespace-only lines make empty paragraphs, which render as
+        // nothing, and aren't counted as paragraphs
+        if is_blank_para(&b) {
+            continue;
+        }
+        match kind_of_block(&b) {
+            BlockKind::Heading      => counts.headings += 1,
+            BlockKind::Paragraph    => counts.paragraphs += 1,
+            BlockKind::Links        => counts.links += 1,
+            BlockKind::Preformatted => counts.preformatted += 1,
+            _ => ()
+        }
+    }
+
+    Ok(counts)
+}
+
+// start every stage but the last, returning where the Blocks come out
+fn start_pipeline(input: Box<dyn BufRead + Send>,
+                  opts: &Options) -> Receiver<Result<NumBlock, Error>> {
+    let (tx1, rx1) = mpsc::channel();
+    read_lines(input, tx1, opts.clone());
+
+    let (tx2, rx2) = mpsc::channel();
+    gather_preformatted(rx1, tx2, opts.clone());
+
+    let (tx3, rx3) = mpsc::channel();
+    decode_lines(rx2, tx3, opts.clone());
+
+    let (tx4, rx4) = mpsc::channel();
+    blocks_of_lines(rx3, tx4, opts.clone());
+
+    let rx5 = match opts.infer_headings {
+        Some(max) => {
+            let (tx5, rx5) = mpsc::channel();
+            infer_headings(rx4, tx5, max);
+            rx5
+        },
+        None => rx4
+    };
+
+    let rx6 = if opts.strip_trailing_links {
+        let (tx6, rx6) = mpsc::channel();
+        strip_trailing_links(rx5, tx6);
+        rx6
+    } else {
+        rx5
+    };
+
+    match opts.section {
+        Some(n) => {
+            let (tx7, rxlast) = mpsc::channel();
+            select_section(rx6, tx7, n);
+            rxlast
+        },
+        None => rx6
+    }
+}
+
+// drop the document's last Block if it is a list of links, typically a
+// footer of links home and so on, counting only what would be visible:
+// empty paragraphs and comments after the links don't save them. Each
+// list of links is held back, with anything invisible after it, until
+// something visible shows that it isn't the last
+fn strip_trailing_links(rx: Receiver<Result<NumBlock, Error>>,
+                        tx: Sender<Result<NumBlock, Error>>) {
+    thread::spawn(move || {
+        let mut held: Vec<NumBlock> = vec![];
+
+        for i in rx {
+            // the links weren't the last Block after all
+            let nb = match i {
+                Ok(nb) => nb,
+                Err(e) => {
+                    for h in held {
+                        send_or_stop!(tx, Ok(h));
+                    }
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            let invisible = is_blank_para(&nb.0) || matches!(nb.0, Block::CommentB(_));
+            if !held.is_empty() && invisible {
+                held.push(nb);
+                continue;
+            }
+            for h in held.drain(..) {
+                send_or_stop!(tx, Ok(h));
+            }
+            if let Block::LinksB(_) = nb.0 {
+                held.push(nb);
+            } else {
+                send_or_stop!(tx, Ok(nb));
+            }
+        }
+
+        // the links go, but not what was held back with them
+        for h in held.into_iter().skip(1) {
+            send_or_stop!(tx, Ok(h));
+        }
+    });
+}
+
+// the last line of the gemtext a Block came from, taking an unclosed
+// preformatted block to have been closed, which errs on the side of there
+// being no blank line after it
+fn last_line_of_block(NumBlock(b, lineno, _): &NumBlock) -> usize {
+    match b {
+        Block::LinksB(ll) => lineno + ll.len() - 1,
+        Block::QuoteB(q) => lineno + q.len() - 1,
+        Block::ListB(items) => lineno + items.len() - 1,
+        Block::PreformattedB(_, prpr) => lineno + prpr.len() + 1,
+        _ => *lineno
+    }
+}
+
+fn is_blank_para(b: &Block) -> bool {
+    matches!(b, Block::ParaB(p) if p.trim().is_empty())
+}
+
+// could this be a title written without "#"? It must be a one-line
+// paragraph of at most max characters, and not read like the end of a
+// sentence
+fn is_heading_like(b: &Block, max: usize) -> bool {
+    match b {
+        Block::ParaB(p) => !is_blank_para(b) && p.chars().count() <= max
+            && !p.ends_with(|c: char| ".,;:!?".contains(c)),
+        _ => false
+    }
+}
+
+// experimental: promote to level 2 headings the paragraphs which look
+// like titles in gemtext written without "#" headings. The heuristic is
+// deliberately cautious: a paragraph is promoted only when
+//  - it is short, and doesn't end in punctuation (see is_heading_like),
+//  - it is the first Block, or has a blank line before it,
+//  - it has a blank line after it, and
+//  - the next Block is a non-empty paragraph, i.e. the title's body text.
+// Blank lines don't come through as Blocks, so they are told from gaps in
+// the line numbers, or from the empty paragraphs of whitespace-only lines.
+// The candidate, and any empty paragraphs after it, are held back until
+// the next Block shows whether it is a heading.
+fn infer_headings(rx: Receiver<Result<NumBlock, Error>>,
+                  tx: Sender<Result<NumBlock, Error>>, max: usize) {
+    thread::spawn(move || {
+        let mut last_line = 0;
+        let mut held: Vec<NumBlock> = vec![];
+
+        for i in rx {
+            let nb = match i {
+                Ok(nb) => nb,
+                Err(e) => {
+                    for h in held {
+                        send_or_stop!(tx, Ok(h));
+                    }
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+
+            if !held.is_empty() {
+                if is_blank_para(&nb.0) {
+                    held.push(nb);
+                    continue;
+                }
+                let rest = held.split_off(1);
+                let NumBlock(cand, n, offset) = held.pop().unwrap();
+                let promote = nb.1 > n + 1 &&
+                    matches!(&nb.0, Block::ParaB(_)) && !is_blank_para(&nb.0);
+                let cand = match cand {
+                    Block::ParaB(p) if promote => Block::HeadingB(Heading(HeadingLevel::H2, p)),
+                    b => b
+                };
+                send_or_stop!(tx, Ok(NumBlock(cand, n, offset)));
+                for h in rest {
+                    send_or_stop!(tx, Ok(h));
+                }
+            }
+
+            if is_blank_para(&nb.0) {
+                send_or_stop!(tx, Ok(nb));
+            } else if is_heading_like(&nb.0, max) && (last_line == 0 || nb.1 > last_line + 1) {
+                last_line = nb.1;
+                held.push(nb);
+            } else {
+                last_line = last_line_of_block(&nb);
+                send_or_stop!(tx, Ok(nb));
+            }
+        }
+
+        for h in held {
+            send_or_stop!(tx, Ok(h));
+        }
+    });
+}
+
+// pass on only the Blocks of the nth section: section 0 is whatever comes
+// before the first level 1 heading, and each such heading starts another
+fn select_section(rx: Receiver<Result<NumBlock, Error>>,
+                  tx: Sender<Result<NumBlock, Error>>, n: usize) {
+    thread::spawn(move || {
+        let mut section = 0;
+
+        for i in rx {
+            let nb = or_forward!(tx, i);
+            if let NumBlock(Block::HeadingB(Heading(HeadingLevel::H1, _)), _, _) = nb {
+                section += 1;
+                if section > n {
+                    return;
+                }
+            }
+            if section == n {
+                send_or_stop!(tx, Ok(nb));
+            }
+        }
+
+        if section < n {
+            let _ = tx.send(Err(Error::NoSuchSection { section: n, sections: section }));
+        }
+    });
+}
+
+// run the whole pipeline over one input; this blocks until the input is
+// used up, so an async caller wants convert_async, with the "async" feature
+//
+// With --embed-source the input is read in whole before anything else, to
+// be kept for the end, so it is held in memory throughout
+pub fn convert(input: Box<dyn BufRead + Send>, out: &mut dyn Write,
+               opts: &Options) -> Result<Summary, Error> {
+    Converter::new(opts).convert(input, out)
+}
+
+// convert, set up a step at a time, for what Options can't say:
+//
+//     Converter::new(&opts).transform(|b| vec![b]).convert(input, &mut out)
+//
+// With a transform, each Block passes through it on its way from being
+// parsed to being rendered; whatever Blocks it returns are rendered in its
+// place, so it may drop, change or add to them, each taking the line number
+// of the Block it came from
+pub struct Converter<'a> {
+    opts: Options,
+    transform: Option<Box<dyn FnMut(Block) -> Vec<Block> + 'a>>
+}
+
+impl<'a> Converter<'a> {
+    pub fn new(opts: &Options) -> Self {
+        Converter { opts: opts.clone(), transform: None }
+    }
+
+    pub fn transform<F>(mut self, f: F) -> Self
+    where F: FnMut(Block) -> Vec<Block> + 'a {
+        self.transform = Some(Box::new(f));
+        self
+    }
+
+    pub fn convert(mut self, mut input: Box<dyn BufRead + Send>,
+                   out: &mut dyn Write) -> Result<Summary, Error> {
+        let opts = &self.opts;
+        let mut source = None;
+        if opts.embed_source {
+            let mut bytes = vec![];
+            input.read_to_end(&mut bytes)?;
+            source = Some(match opts.encoding {
+                Encoding::Utf8 => String::from_utf8_lossy(&bytes).into_owned(),
+                Encoding::Cp1252 => string_of_cp1252(&bytes)
+            });
+            input = Box::new(io::Cursor::new(bytes));
+        }
+        let rx = start_pipeline(input, opts);
+
+        let transform = match &mut self.transform {
+            Some(t) => t,
+            None => return consume_blocks(rx, out, opts, source.as_deref())
+        };
+        let blocks = rx.into_iter().flat_map(|i| match i {
+            Ok(NumBlock(b, lineno, offset)) =>
+                transform(b).into_iter().map(|b| Ok(NumBlock(b, lineno, offset))).collect(),
+            Err(e) => vec![Err(e)]
+        });
+        consume_blocks(blocks, out, opts, source.as_deref())
+    }
+}
+
+// convert, but starting afresh at each level 1 heading; sections with
+// nothing worth writing are left out
+pub fn split_on_h1(input: Box<dyn BufRead + Send>,
+                   opts: &Options) -> Result<Vec<Section>, Error> {
+    let mut groups: Vec<(Option<String>, Vec<NumBlock>)> = vec![(None, vec![])];
+
+    for i in start_pipeline(input, opts) {
+        let nb = i?;
+        if let NumBlock(Block::HeadingB(Heading(HeadingLevel::H1, t)), _, _) = &nb {
+            groups.push((Some(t.clone()), vec![]));
+        }
+        groups.last_mut().unwrap().1.push(nb);
+    }
+
+    let mut sections = vec![];
+    for (title, blocks) in groups {
+        let mut markdown = vec![];
+        let summary = consume_blocks(blocks.into_iter().map(Ok), &mut markdown, opts, None)?;
+        if summary.wrote_any {
+            sections.push(Section { title, markdown, summary });
+        }
+    }
+
+    Ok(sections)
+}
+
+// convert, for any reader and writer; the input is read a line at a time
+// as the conversion goes, rather than all at once
+pub fn convert_reader_to_writer<R, W>(input: R, mut output: W,
+                                      opts: &Options) -> Result<Summary, Error>
+where R: BufRead + Send + 'static, W: Write {
+    convert(Box::new(input), &mut output, opts)
+}
+
+// convert a whole document held in memory, with the default options
+pub fn gemtext_to_markdown(input: &str) -> Result<String, Error> {
+    let mut out = vec![];
+    convert_reader_to_writer(io::Cursor::new(input.as_bytes().to_vec()), &mut out,
+                             &Options::default())?;
+    // the input was valid UTF-8, and the conversion only adds ASCII to it,
+    // so nothing is lost here
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+// the Blocks a document comes to, with the default options, before they're
+// rendered; for looking at what the parsing made of it
+pub fn parse_to_blocks(input: &str) -> Result<Vec<Block>, Error> {
+    let input = io::Cursor::new(input.as_bytes().to_vec());
+    start_pipeline(Box::new(input), &Options::default()).into_iter()
+        .map(|i| i.map(|NumBlock(b, _, _)| b))
+        .collect()
+}
+
+// the Blocks as JSON Lines, one object per Block, rather than Markdown
+pub fn convert_jsonl(input: Box<dyn BufRead + Send>, out: &mut dyn Write,
+                     opts: &Options) -> Result<(), Error> {
+    jsonl_blocks(start_pipeline(input, opts), out, opts)
+}
+
+// HTML rather than Markdown, for --html
+pub fn convert_html(input: Box<dyn BufRead + Send>, out: &mut dyn Write,
+                    opts: &Options) -> Result<(), Error> {
+    html_blocks(start_pipeline(input, opts), out, opts)
+}
+
+// each Block on a single line, for --oneline
+pub fn convert_oneline(input: Box<dyn BufRead + Send>, out: &mut dyn Write,
+                       opts: &Options) -> Result<(), Error> {
+    oneline_blocks(start_pipeline(input, opts), out, opts)
+}
+
+// the text of the first level 1 heading; the rest of the input isn't read,
+// as dropping the channel brings the pipeline to a stop
+pub fn title(input: Box<dyn BufRead + Send>, opts: &Options) -> Result<Option<String>, Error> {
+    for i in start_pipeline(input, opts) {
+        if let NumBlock(Block::HeadingB(Heading(HeadingLevel::H1, t)), _, _) = i? {
+            return Ok(Some(t));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn count(input: Box<dyn BufRead + Send>, opts: &Options) -> Result<Counts, Error> {
+    count_blocks(start_pipeline(input, opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn md(input: &str) -> String {
+        gemtext_to_markdown(input).unwrap()
+    }
+
+    fn md_with(input: &str, opts: &Options) -> String {
+        let mut out = vec![];
+        convert_reader_to_writer(io::Cursor::new(input.as_bytes().to_vec()), &mut out, opts)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // with no final newline, the last line is converted all the same, and
+    // the output still ends with the usual blank line
+    #[test]
+    fn no_final_newline() {
+        assert_eq!(md("para"), "para\n\n");
+        assert_eq!(md("# Head"), "# Head\n\n");
+        assert_eq!(md("=> /x X"), "* [X](/x)\n\n");
+        assert_eq!(md("```\ncode\n```"), "```\ncode\n```\n\n");
+    }
+
+    // the riskiest case, as the unclosed block is only flushed at the end
+    // of the input
+    #[test]
+    fn no_final_newline_in_preformatted() {
+        assert_eq!(md("```\ncode"), "```\ncode\n```\n\n");
+        assert_eq!(md("text\n```\nab\ncd"), "text\n\n```\nab\ncd\n```\n\n");
+        assert_eq!(parse_to_blocks("```rust\ncode").unwrap(),
+                   vec![Block::PreformattedB(Some("rust".to_string()), vec!["code".to_string()])]);
+    }
+
+    #[test]
+    fn no_final_newline_blocks() {
+        let blocks = parse_to_blocks("# H\npara\n=> /x X").unwrap();
+        assert_eq!(blocks, vec![
+            Block::HeadingB(Heading::new(HeadingLevel::H1, "H")),
+            Block::ParaB("para".to_string()),
+            Block::LinksB(vec![Link("/x".to_string(), Some("X".to_string()))])
+        ]);
+    }
+
+    // what follows the hashes is the heading's text, never parsed again
+    #[test]
+    fn heading_text_is_literal() {
+        assert_eq!(parse_to_blocks("# => x\n").unwrap(),
+                   vec![Block::HeadingB(Heading::new(HeadingLevel::H1, "=> x"))]);
+        assert_eq!(parse_to_blocks("## ```\n").unwrap(),
+                   vec![Block::HeadingB(Heading::new(HeadingLevel::H2, "```"))]);
+        assert_eq!(md("# => x\n"), "# => x\n\n");
+        assert_eq!(md("## ```\ntext\n"), "## ```\n\ntext\n\n");
+        assert_eq!(md("### # not a heading\n"), "### # not a heading\n\n");
+    }
+
+    #[test]
+    fn compact_heading_then_links() {
+        let input = "# H\n=> /a A\n=> /b B\n";
+        assert_eq!(md(input), "# H\n\n* [A](/a)\n* [B](/b)\n\n");
+        let opts = Options { compact: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts), "# H\n* [A](/a)\n* [B](/b)\n\n");
+        assert_eq!(md_with("# H\n* a\n* b\n", &opts), "# H\n* a\n* b\n\n");
+        // only after a heading
+        assert_eq!(md_with("text\n=> /a A\n", &opts), "text\n\n* [A](/a)\n\n");
+    }
+
+    // the spec wants structural lines at the start of the line, so strictly
+    // an indented one is a paragraph
+    #[test]
+    fn lenient_headings() {
+        let lenient = Options { lenient_headings: true, ..Options::default() };
+        assert_eq!(parse_to_blocks("  # x\n").unwrap(), vec![Block::ParaB("# x".to_string())]);
+        assert_eq!(md("  # x\n"), "\\# x\n\n");
+        assert_eq!(md_with("  # x\n", &lenient), "# x\n\n");
+        assert_eq!(md("\t=> /url\n"), "=> /url\n\n");
+        assert_eq!(md_with("\t=> /url\n", &lenient), "* [/url](/url)\n\n");
+    }
+
+    #[test]
+    fn cp1252_punctuation() {
+        let input = b"\x93Quoted\x94, \x91single\x92 \x97 and \x96 dashes\n".to_vec();
+        let opts = Options { encoding: Encoding::Cp1252, ..Options::default() };
+        let mut out = vec![];
+        convert_reader_to_writer(io::Cursor::new(input.clone()), &mut out, &opts).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\u{201c}Quoted\u{201d}, \u{2018}single\u{2019} \u{2014} and \u{2013} dashes\n\n");
+
+        // strict UTF-8 is the default
+        let mut out = vec![];
+        let result = convert_reader_to_writer(io::Cursor::new(input), &mut out,
+                                              &Options::default());
+        assert!(matches!(result, Err(Error::Io(e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn all_blank_document() {
+        for input in ["", "\n", "\n\n\n", "\r\n\r\n"] {
+            assert_eq!(md(input), "");
+            assert_eq!(parse_to_blocks(input).unwrap(), vec![]);
+        }
+        // whitespace-only lines make empty paragraphs, which render as nothing
+        assert_eq!(md("  \n\t\n"), "");
+    }
+
+    // the alt text belongs to its own block, and doesn't leak into the next
+    #[test]
+    fn alt_text_per_block() {
+        let input = "```rust\nfn f() {}\n```\n```python\ndef f(): pass\n```\n```\nplain\n```\n";
+        let opts = Options { pre_alt: PreAlt::Info, ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   "```rust\nfn f() {}\n```\n\n```python\ndef f(): pass\n```\n\n```\nplain\n```\n\n");
+        let alts: Vec<Option<String>> = parse_to_blocks(input).unwrap().into_iter()
+            .map(|b| match b { Block::PreformattedB(alt, _) => alt, _ => panic!("{:?}", b) })
+            .collect();
+        assert_eq!(alts, vec![Some("rust".to_string()), Some("python".to_string()), None]);
+    }
+
+    #[test]
+    fn trim_pre_trailing() {
+        let input = "```\n  indented  \n\t\n\nend\t \n```\n";
+        assert_eq!(md(input), "```\n  indented  \n\t\n\nend\t \n```\n\n");
+        let opts = Options { trim_pre_trailing: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts), "```\n  indented\n\n\nend\n```\n\n");
+    }
+
+    // an empty quote line stays within the quotation; a blank line ends it
+    #[test]
+    fn empty_quote_line() {
+        let q = |lines: &[&str]| Block::QuoteB(lines.iter().map(|l| l.to_string()).collect());
+        assert_eq!(parse_to_blocks("> a\n>\n> b\n").unwrap(), vec![q(&["a", "", "b"])]);
+        assert_eq!(parse_to_blocks("> a\n> \n> b\n\n> c\n").unwrap(),
+                   vec![q(&["a", "", "b"]), q(&["c"])]);
+        assert_eq!(md("> a\n>\n> b\n"), "> a\n>\n> b\n\n");
+        assert_eq!(md("> a\n> \n> b\n\n> c\n"), "> a\n>\n> b\n\n> c\n\n");
+        assert_eq!(md(">\n"), ">\n\n");
+    }
+
+    #[test]
+    fn dotslash() {
+        let input = "=> foo.gmi F\n=> /abs A\n=> #frag H\n=> ./already D\n=> https://x.org/ X\n";
+        let opts = Options { dotslash: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   "* [F](./foo.gmi)\n* [A](/abs)\n* [H](#frag)\n* [D](./already)\n\
+                    * [X](https://x.org/)\n\n");
+        assert_eq!(md("=> foo.gmi F\n"), "* [F](foo.gmi)\n\n");
+    }
+
+    // as from a truncated download: nothing is lost, and the fence is closed
+    #[test]
+    fn unterminated_preformatted_document() {
+        let input = "```sh\nline 1\n\n# line 3\n=> not a link\n";
+        let opts = Options { quiet: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts), "```\nline 1\n\n# line 3\n=> not a link\n```\n\n");
+        assert_eq!(parse_to_blocks(input).unwrap(), vec![Block::PreformattedB(
+            Some("sh".to_string()),
+            ["line 1", "", "# line 3", "=> not a link"].map(String::from).to_vec())]);
+    }
+
+    // nothing is reordered: not the links within a list, nor prose and
+    // links relative to each other
+    #[test]
+    fn links_keep_their_order() {
+        let link = |u: &str, l: &str| Link(u.to_string(), Some(l.to_string()));
+        let para = |p: &str| Block::ParaB(p.to_string());
+        let input = "one\n=> /z Z\n=> /a A\ntwo\n=> /m M\nthree\n";
+        assert_eq!(parse_to_blocks(input).unwrap(), vec![
+            para("one"),
+            Block::LinksB(vec![link("/z", "Z"), link("/a", "A")]),
+            para("two"),
+            Block::LinksB(vec![link("/m", "M")]),
+            para("three")
+        ]);
+        assert_eq!(md(input),
+                   "one\n\n* [Z](/z)\n* [A](/a)\n\ntwo\n\n* [M](/m)\n\nthree\n\n");
+        assert_eq!(md("=> /b B\n\nprose\n\n=> /a A\n"),
+                   "* [B](/b)\n\nprose\n\n* [A](/a)\n\n");
+    }
+
+    #[test]
+    fn toc_depth() {
+        let input = "# A\n## B\n### C\n## D\n";
+        let body = "# A\n\n## B\n\n### C\n\n## D\n\n";
+        let opts = Options { toc: true, toc_depth: Some(2), ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   format!("* [A](#a)\n  * [B](#b)\n  * [D](#d)\n\n{}", body));
+        let opts = Options { toc_indent: Some(4), ..opts };
+        assert_eq!(md_with(input, &opts),
+                   format!("* [A](#a)\n    * [B](#b)\n    * [D](#d)\n\n{}", body));
+        let opts = Options { toc: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   format!("* [A](#a)\n  * [B](#b)\n    * [C](#c)\n  * [D](#d)\n\n{}", body));
+    }
+
+    #[test]
+    fn closed_headings() {
+        let opts = Options { closed_headings: true, ..Options::default() };
+        assert_eq!(md_with("# One\n## Two\n### Three\n", &opts),
+                   "# One #\n\n## Two ##\n\n### Three ###\n\n");
+        assert_eq!(md("# One\n"), "# One\n\n");
+    }
+
+    // the first block goes out at its closing toggle, so the two never merge
+    #[test]
+    fn preformatted_blocks_either_side_of_prose() {
+        let input = "```\ncode 1\n```\nprose\n```\ncode 2\n```\n";
+        assert_eq!(parse_to_blocks(input).unwrap(), vec![
+            Block::PreformattedB(None, vec!["code 1".to_string()]),
+            Block::ParaB("prose".to_string()),
+            Block::PreformattedB(None, vec!["code 2".to_string()])
+        ]);
+        assert_eq!(md(input), "```\ncode 1\n```\n\nprose\n\n```\ncode 2\n```\n\n");
+    }
+
+    #[test]
+    fn no_links() {
+        let opts = Options { no_links: true, ..Options::default() };
+        assert_eq!(md_with("=> url label\n", &opts), "=> url label\n\n");
+        // an empty link is just text too, rather than malformed
+        assert_eq!(md_with("=>\n", &opts), "=>\n\n");
+        assert_eq!(md("=> url label\n"), "* [label](url)\n\n");
+    }
+
+    #[test]
+    fn code_indent_tab() {
+        let input = "```\nfn f() {\n    g();\n\n}\n```\n";
+        let spaces = Options { indent_code: true, ..Options::default() };
+        assert_eq!(md_with(input, &spaces), "    fn f() {\n        g();\n\n    }\n\n");
+        let tab = Options { code_indent: CodeIndent::Tab, ..spaces };
+        assert_eq!(md_with(input, &tab), "\tfn f() {\n\t    g();\n\n\t}\n\n");
+        // only indented code is affected
+        let fenced = Options { code_indent: CodeIndent::Tab, ..Options::default() };
+        assert_eq!(md_with(input, &fenced), "```\nfn f() {\n    g();\n\n}\n```\n\n");
+    }
+
+    // URLs can't contain whitespace, so the first run of it ends the URL
+    #[test]
+    fn link_url_label_boundary() {
+        let link = |u: &str, l: &str| Line::LinkL(Link(u.to_string(), Some(l.to_string())));
+        let line = |s: &str| Line::from(s.to_string());
+        assert_eq!(line("=> /path with spaces label"), link("/path", "with spaces label"));
+        assert_eq!(line("=> /path\twith spaces label"), link("/path", "with spaces label"));
+        assert_eq!(line("=>   /path  \t  with spaces label  "), link("/path", "with spaces label"));
+        assert_eq!(line("=> /path \t"), Line::LinkL(Link("/path".to_string(), None)));
+        assert_eq!(md("=> /path with spaces label\n"), "* [with spaces label](/path)\n\n");
+    }
+
+    #[test]
+    fn numbered_links() {
+        let input = "=> /a A\n=> /b B\n\n=> /c C\n";
+        let per_block = Options { numbered_links: Some(LinkNumbering::PerBlock),
+                                  ..Options::default() };
+        assert_eq!(md_with(input, &per_block), "1. [A](/a)\n2. [B](/b)\n\n1. [C](/c)\n\n");
+        let continuous = Options { numbered_links: Some(LinkNumbering::Continuous),
+                                   ..Options::default() };
+        assert_eq!(md_with(input, &continuous), "1. [A](/a)\n2. [B](/b)\n\n3. [C](/c)\n\n");
+    }
+
+    #[test]
+    fn escape_heading_text() {
+        let opts = Options { escape: true, ..Options::default() };
+        assert_eq!(md_with("## ```\n", &opts), "## \\`\\`\\`\n\n");
+        assert_eq!(md_with("## => x\n", &opts), "## =\\> x\n\n");
+        assert_eq!(md_with("## [x](y)\n", &opts), "## \\[x\\](y)\n\n");
+        // literal by default
+        assert_eq!(md("## ```\n"), "## ```\n\n");
+        assert_eq!(md("## => x\n"), "## => x\n\n");
+    }
+
+    #[test]
+    fn text_schemes() {
+        let input = "=> gopher://g.org/1/ Gopher hole\n=> https://x.org/ Web\n=> /rel Rel\n";
+        assert_eq!(md(input),
+                   "* [Gopher hole](gopher://g.org/1/)\n* [Web](https://x.org/)\n* [Rel](/rel)\n\n");
+        let opts = Options { text_schemes: vec!["gopher".to_string(), "finger".to_string()],
+                             ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   "* Gopher hole\n* [Web](https://x.org/)\n* [Rel](/rel)\n\n");
+    }
+
+    // an empty or whitespace label is no label at all
+    #[test]
+    fn empty_link_label() {
+        let link = |label: &str| Link("/a/b.gmi".to_string(), Some(label.to_string()));
+        assert_eq!(link("").to_string(), "* [/a/b.gmi](/a/b.gmi)\n");
+        assert_eq!(link("   ").to_string(), "* [/a/b.gmi](/a/b.gmi)\n");
+        let opts = Options { no_label: NoLabel::Path, ..Options::default() };
+        assert_eq!(string_of_link(&link("   "), "*", &opts), "* [b.gmi](/a/b.gmi)\n");
+    }
+
+    // only "* ", star then space, at the start of the line is a list item
+    #[test]
+    fn list_items() {
+        assert_eq!(parse_to_blocks("* item\n*emphasis*\n").unwrap(), vec![
+            Block::ListB(vec!["item".to_string()]),
+            Block::ParaB("*emphasis*".to_string())
+        ]);
+        assert_eq!(md("* item\n*emphasis*\n"), "* item\n\n*emphasis*\n\n");
+        let opts = Options { no_lists: true, ..Options::default() };
+        assert_eq!(md_with("* item\n", &opts), "\\* item\n\n");
+    }
+
+    // only a bounded prefix is looked at to decide what a line is
+    #[test]
+    fn very_long_line() {
+        let long = "x".repeat(4 << 20);
+        assert_eq!(md(&long), format!("{}\n\n", long));
+        assert_eq!(md(&format!("## {}", long)), format!("## {}\n\n", long));
+        assert_eq!(Line::from(format!("=> /u {}", long)),
+                   Line::LinkL(Link("/u".to_string(), Some(long.clone()))));
+    }
+
+    #[test]
+    fn subtitles() {
+        let opts = Options { subtitle: true, ..Options::default() };
+        assert_eq!(md("# A\n## B\ntext\n"), "# A\n\n## B\n\ntext\n\n");
+        assert_eq!(md_with("# A\n## B\ntext\n", &opts), "# A\n\n*B*\n\ntext\n\n");
+        // not after a blank line, nor under a lower level heading
+        assert_eq!(md_with("# A\n\n## B\n", &opts), "# A\n\n## B\n\n");
+        assert_eq!(md_with("## A\n# B\n", &opts), "## A\n\n# B\n\n");
+        // and a subtitle has no subtitle of its own
+        assert_eq!(md_with("# A\n## B\n### C\n", &opts), "# A\n\n*B*\n\n### C\n\n");
+    }
+
+    // the padding is kept, where Markdown would collapse it when rendered
+    #[test]
+    fn preserve_link_whitespace() {
+        let input = "=> /a Name    Size   Date  \n";
+        assert_eq!(md(input), "* [Name    Size   Date](/a)\n\n");
+        let opts = Options { preserve_link_whitespace: true, ..Options::default() };
+        assert_eq!(md_with(input, &opts),
+                   "* [Name &nbsp;&nbsp;&nbsp;Size &nbsp;&nbsp;Date](/a)\n\n");
+    }
+
+    #[test]
+    fn heading_text_trimmed() {
+        let h2 = |t: &str| Line::HeadingL(Heading::new(HeadingLevel::H2, t));
+        for s in ["## Heading   ", "## Heading\t\t", "## Heading\r", "##   Heading \t\r"] {
+            assert_eq!(Line::from(s.to_string()), h2("Heading"));
+        }
+        assert_eq!(md("## Heading \t\r\n"), "## Heading\n\n");
+        // nothing but whitespace is no heading
+        assert!(matches!(Line::from("#  \t".to_string()),
+                         Line::MalformedL(Malformed::MHeading, _)));
+    }
+
+    #[test]
+    fn show_urls() {
+        let input = "=> https://x.org/ Caption\n=> https://y.org/\n";
+        let inside = Options { show_urls: Some(ShowUrls::Inside), ..Options::default() };
+        assert_eq!(md_with(input, &inside),
+                   "* [Caption (https://x.org/)](https://x.org/)\n* [https://y.org/](https://y.org/)\n\n");
+        let after = Options { show_urls: Some(ShowUrls::After), ..Options::default() };
+        assert_eq!(md_with(input, &after),
+                   "* [Caption](https://x.org/) (https://x.org/)\n* [https://y.org/](https://y.org/)\n\n");
+    }
+
+    // the library's default spacing is the commandline's
+    #[test]
+    fn default_block_spacing() {
+        assert_eq!(md("a\n   \nb\n"), "a\n\nb\n\n");
+        let opts = Options { block_spacing: Some(2), ..Options::default() };
+        assert_eq!(md_with("a\n   \nb\n", &opts), "a\n\n\nb\n\n");
+    }
+
+    #[test]
+    fn pre_to_table() {
+        let opts = Options { pre_to_table: true, ..Options::default() };
+        let table = "| a | b |\n| --- | --- |\n| 1 | 2 |\n\n";
+        assert_eq!(md_with("```\na | b\n--|--\n1 | 2\n```\n", &opts), table);
+        assert_eq!(md_with("```\n| a | b |\n| 1 | 2 |\n```\n", &opts), table);
+        assert_eq!(md_with("```\na   b\n1   2\n```\n", &opts), table);
+        // shell pipes aren't columns
+        let shell = "```\ncat x | grep y\nls | wc -l\n```\n";
+        assert_eq!(md_with(shell, &opts), format!("{}\n", shell));
+    }
+
+    #[test]
+    fn count_skips_blank_paragraphs() {
+        let input = io::Cursor::new(b"a\n   \n\t\nb\n".to_vec());
+        let counts = count(Box::new(input), &Options::default()).unwrap();
+        assert_eq!(counts.to_string(), "headings=0 paragraphs=2 links=0 preformatted=0");
+    }
+
+    #[test]
+    fn preserve_empty_links() {
+        let opts = Options { preserve_empty_links: true, ..Options::default() };
+        assert_eq!(md_with("=>\n=>  \n", &opts),
+                   "<!-- empty link: \"=>\" -->\n\n<!-- empty link: \"=>\" -->\n\n");
+        // a link with no space after the arrow isn't empty, just malformed
+        let mut out = vec![];
+        let result = convert_reader_to_writer(io::Cursor::new(b"=>http://x\n".to_vec()),
+                                              &mut out, &opts);
+        assert!(matches!(result, Err(Error::Malformed { line: 1, kind: Malformed::MLink })));
+    }
+
+    #[test]
+    fn smart_links_escape_text() {
+        let opts = Options { smart_links: true, links: LinkStyle::Text, ..Options::default() };
+        assert_eq!(md_with("=> /a 1. First\n\n=> /b - dash\n\n=> /c ---\n", &opts),
+                   "1\\. First\n\n\\- dash\n\n\\---\n\n");
+        assert_eq!(md_with("=> /a 2024 was\n", &opts), "2024 was\n\n");
+    }
+
+    #[test]
+    fn byte_offsets() {
+        let input = "# T\r\nhello\n\n=> a b\n```\ncode\n```\n\u{e9}t\u{e9}\n";
+        let opts = Options { byte_offsets: true, ..Options::default() };
+        let mut out = vec![];
+        convert_jsonl(Box::new(io::Cursor::new(input.as_bytes().to_vec())), &mut out, &opts)
+            .unwrap();
+        let offsets: Vec<&str> = std::str::from_utf8(&out).unwrap().lines()
+            .map(|l| l.split("\"byte_offset\": ").nth(1).unwrap().split(',').next().unwrap())
+            .collect();
+        assert_eq!(offsets, vec!["0", "5", "12", "19", "32"]);
+    }
+
+    // what came before a malformed line is still converted, links included
+    #[test]
+    fn malformed_line_after_links() {
+        let input = "text\n=> /a A\n=> /b B\n#bad\n";
+        let mut out = vec![];
+        let result = convert_reader_to_writer(io::Cursor::new(input.as_bytes().to_vec()),
+                                              &mut out, &Options::default());
+        assert!(matches!(result, Err(Error::Malformed { line: 4, kind: Malformed::MHeading })));
+        assert_eq!(String::from_utf8(out).unwrap(), "text\n\n* [A](/a)\n* [B](/b)\n");
+
+        let blocks: Vec<_> = start_pipeline(Box::new(io::Cursor::new("> q\n#bad\n")),
+                                            &Options::default()).into_iter().collect();
+        assert!(matches!(&blocks[..], [Ok(NumBlock(Block::QuoteB(_), 1, 0)), Err(_)]));
+    }
+
+    // with another fence, "```" is just text, and mustn't open a Markdown one
+    #[test]
+    fn backticks_with_source_fence() {
+        let opts = Options { source_fence: Some("~~~".to_string()), ..Options::default() };
+        assert_eq!(md_with("```\nafter\n# H\n", &opts), "\\```\n\nafter\n\n# H\n\n");
+        assert_eq!(md_with("~~~\ncode\n~~~\n", &opts), "```\ncode\n```\n\n");
+        let opts = Options { source_fence: Some("%%%".to_string()), ..Options::default() };
+        assert_eq!(md_with("~~~ rust\n", &opts), "\\~~~ rust\n\n");
+    }
+
+
+    // indented code straight after a list would otherwise join its last item
+    #[test]
+    fn indent_code_after_list() {
+        let opts = Options { indent_code: true, ..Options::default() };
+        assert_eq!(md_with("=> /a A\n```\ncode\n```\n", &opts),
+                   "* [A](/a)\n\n<!-- -->\n\n    code\n\n");
+        assert_eq!(md_with("* i\n```\ncode\n```\n", &opts),
+                   "* i\n\n<!-- -->\n\n    code\n\n");
+        assert_eq!(md_with("text\n```\ncode\n```\n", &opts), "text\n\n    code\n\n");
+    }
+
+
+    // the markers are dropped, so the gemtext is kept apart from the host's
+    // text by a blank line at least
+    #[test]
+    fn embedded_boundaries() {
+        let opts = Options { embedded: true, ..Options::default() };
+        assert_eq!(md_with("host para\nmore\n<!-- gemtext -->\ngem para\n<!-- /gemtext -->\n\
+                            host after\n\n\nhost2\n", &opts),
+                   "host para\nmore\n\ngem para\n\nhost after\n\n\nhost2\n");
+    }
+
+
+    // --canonical promises LF line endings, whatever the header has
+    #[test]
+    fn canonical_header_with_crlf() {
+        let opts = Options { canonical: true, header: Some("Top \r\nline\r\n\r\n".to_string()),
+                             ..Options::default() };
+        assert_eq!(md_with("# T\n", &opts), "Top\nline\n\n# T\n");
+        assert_eq!(md_with("a\rb \r\n", &opts), "Top\nline\n\na\rb\n");
+    }
+
+
+    fn md_transformed<F>(input: &str, opts: &Options, f: F) -> String
+    where F: FnMut(Block) -> Vec<Block> {
+        let mut out = vec![];
+        Converter::new(opts).transform(f)
+            .convert(Box::new(io::Cursor::new(input.as_bytes().to_vec())), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn transform_filters_changes_and_adds() {
+        let input = "# Title\n\nsome text\n> a quote\n";
+        let out = md_transformed(input, &Options::default(), |b| match b {
+            Block::QuoteB(_) => vec![],
+            Block::ParaB(t) => vec![Block::ParaB(t.to_uppercase())],
+            b @ Block::HeadingB(_) => vec![b, Block::ParaB("added".to_string())],
+            b => vec![b]
+        });
+        assert_eq!(out, "# Title\n\nadded\n\nSOME TEXT\n\n");
+    }
+
+    // the source embedded is the input, not the transformed Blocks
+    #[test]
+    fn transform_with_embed_source() {
+        let opts = Options { embed_source: true, ..Options::default() };
+        let out = md_transformed("a\n", &opts, |_| vec![Block::ParaB("b".to_string())]);
+        assert_eq!(out, "b\n\n<!-- GEMTEXT SOURCE\na\n-->\n");
+    }
+
+
+    // Line::from as it was, matching on chars, for the byte matching to be
+    // checked against
+    fn line_by_chars(s: String) -> Line {
+        use Line::*;
+        use Malformed::*;
+        use HeadingLevel::*;
+
+        match s.chars().collect::<Vec<char>>()[..] {
+            ['=', '>']               => MalformedL(MLink, s),
+            ['=', '>', ' ', ..]      => link_of_line(s),
+            ['=', '>', ..]           => MalformedL(MLink, s),
+            ['#', '#', '#']          => MalformedL(MHeading, s),
+            ['#', '#', '#', ' ']     => MalformedL(MHeading, s),
+            ['#', '#', '#', ' ', ..] => make_heading(s, H3, 4),
+            ['#', '#', '#', ..]      => make_heading(s, H3, 3),
+            ['#', '#', _]            => MalformedL(MHeading, s),
+            ['#', '#']               => MalformedL(MHeading, s),
+            ['#', '#', ' ', ..]      => make_heading(s, H2, 3),
+            ['#', '#', ..]           => MalformedL(MHeading, s),
+            ['#', ' ']               => MalformedL(MHeading, s),
+            ['#']                    => MalformedL(MHeading, s),
+            ['#', ' ', ..]           => make_heading(s, H1, 2),
+            ['#', ..]                => MalformedL(MHeading, s),
+            ['>', ..]                => QuoteL(s[1..].trim().to_string()),
+            ['*', ' ', ..]           => ListL(s[2..].trim().to_string()),
+            []                       => BlankL,
+            _                        => ParaL(trim(s))
+        }
+    }
+
+    #[test]
+    fn line_markers_match_on_bytes_as_on_chars() {
+        let lines = ["", "é", "é#", "=>", "=>é", "=> é", "=> /a é", "=>/a",
+                     "#", "# ", "#é", "# é", "##", "## ", "##é", "## é", "##é ",
+                     "###", "### ", "###é", "### é", "####é", ">", ">é", "> é",
+                     "*", "*é", "* é", "**", " # é"];
+        for l in lines {
+            assert_eq!(Line::from(l.to_string()), line_by_chars(l.to_string()), "{:?}", l);
+        }
+        assert_eq!(Line::from("##é".to_string()),
+                   Line::MalformedL(Malformed::MHeading, "##é".to_string()));
+        assert_eq!(Line::from("###é".to_string()),
+                   Line::HeadingL(Heading::new(HeadingLevel::H3, "é")));
+    }
+
+
+    // a caption shown as text mustn't turn its item into a heading or list
+    #[test]
+    fn text_link_captions_escaped() {
+        let opts = Options { links: LinkStyle::Text, ..Options::default() };
+        assert_eq!(md_with("=> /a # Heading\n=> /b - x\n=> /c 1. y\n=> /d plain\n", &opts),
+                   "* \\# Heading\n* \\- x\n* 1\\. y\n* plain\n\n");
+    }
+}
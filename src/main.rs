@@ -5,302 +5,607 @@
    This programme is free software; you may redistribute and/or modify it under
    the terms of the Apache Software Licence v2.0. */
 
-/* Usage: as a commandline filter. The program reads Gemtext on stdin and
-   outputs Markdown, hopefully in CommonMark format. */
-
-/* The author is perfectly aware that this code is unidiomatic,
-   inefficient, inelegant, unprincipled, undocumented, depends on
-   loading the whole input stream into memory, makes your curtains
-   fade, etc, etc. It is simply not worth polishing it. */
-
-/* The code below is a fairly bloody-minded hand translation from
-   the OCaml original into unidiomatic Rust, right down to the function
-   names. */
-
-use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
-use std::thread;
-use std::io::{self, BufRead, Write};
-use std::fmt;
-
-#[derive(Debug)]
-enum HeadingLevel {
-    H1,
-    H2,
-    H3
+/* Usage: as a commandline filter. The program reads Gemtext on stdin, or
+   from the file named on the commandline, and outputs Markdown, hopefully
+   in CommonMark format. */
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::env;
+use std::process::ExitCode;
+use std::fmt::Display;
+
+#[cfg(feature = "fetch")]
+mod fetch;
+
+use gemtext2md::{convert, convert_html, convert_jsonl, convert_oneline, count, json_string,
+                 slugify, split_on_h1, title, warn, BlockKind, CodeIndent, Encoding, Eol, Error,
+                 LeadParagraph, LinkNumbering, LinkStyle, LinksFrontmatter, NoLabel,
+                 Options, PreAlt, Sanitize, Section, ShowUrls, Summary};
+
+const HELP: &str = "\
+usage: gemtext2md [OPTION]... [FILE]
+       gemtext2md [OPTION]... --recursive SRC --out-dir DST
+       gemtext2md [OPTION]... --split-on-h1 --out-dir DST [FILE]
+
+Converts gemtext, from FILE or stdin, to Markdown on stdout. The options
+are described in README.md:
+
+  --autolink-bare-urls --block-spacing N --byte-offsets --canonical
+  --closed-headings --code-indent spaces|tab --compact
+  --continue-numbering --count-only --dedup-links --detect-lang
+  --dotslash --embed-source --embed-under N --embedded
+  --encoding utf8|cp1252 --eol lf|crlf --escape --exact-dup-headings
+  --fail-on-empty --footer-file FILE --force --header-file FILE --help
+  --html --html-source-attrs --indent-code --infer-headings
+  --infer-headings-max N --jsonl --keep-close-alt
+  --lead-paragraph frontmatter|class|none --lenient-headings
+  --link-titles --links markdown|text --links-frontmatter
+  --links-frontmatter-labels --lint-links --list-indent N
+  --literal-fences --manifest FILE --mark-malformed --max-blocks N
+  --no-blank-between-links-and-heading --no-label url|path|fixed:TEXT
+  --no-links --no-lists --normalize-urls --number-headings
+  --numbered-links --oneline --only TYPE,... --out-dir DST
+  --pre-alt info|comment|caption|drop --pre-caption --pre-to-table
+  --preserve-empty-links --preserve-link-whitespace --print-title
+  --progress --progress-every N --provenance --provenance-text TEXT
+  --quiet --recursive SRC --reset-pre-on-blank N
+  --sanitize-control drop|replace --sanitize-pre --scheme-badge
+  --section N --sentence-breaks --show-urls --show-urls-after
+  --simplify-links --slug-from-filename --smart-links --source-fence STR
+  --split-on-h1 --squeeze-spaces --stream --strip-ansi
+  --strip-trailing-links --subtitle --text-schemes SCHEME,... --toc
+  --toc-depth N --toc-indent N --trim-heading-trailing-hashes
+  --trim-pre-trailing --unescape-labels --validate --warn-dup-headings
+  --warn-link-run N --wrap N --wrap-indent N
+
+Exit status:
+  0  success
+  1  an I/O error, such as a file which can't be read or written
+  2  the input can't be converted: it is malformed, or empty with
+     --fail-on-empty, or has no title for --print-title
+  3  a usage error, such as an unknown option, or a --section N beyond
+     the last section of the input
+";
+
+// exit statuses, as listed in HELP
+const EXIT_IO: u8 = 1;
+const EXIT_PARSE: u8 = 2;
+const EXIT_USAGE: u8 = 3;
+
+fn exit_status(e: &Error) -> u8 {
+    match e {
+        Error::Io(_) => EXIT_IO,
+        // the input is fine, it's the --section asked for that is wrong
+        Error::NoSuchSection { .. } => EXIT_USAGE,
+        _ => EXIT_PARSE
+    }
 }
 
-#[derive(Debug)]
-enum Malformed {
-    MLink,
-    MHeading
+fn fail(status: u8, e: impl Display) -> ExitCode {
+    eprintln!("gemtext2md: {}", e);
+    ExitCode::from(status)
 }
 
-#[derive(Debug)]
-struct Heading(HeadingLevel, String);
-
-#[derive(Debug,Clone)]
-struct Link(String, Option<String>);
-
-#[derive(Debug)]
-enum Line {
-    PreformattedL(Vec<String>),
-    ParaL(String),
-    LinkL(Link),
-    HeadingL(Heading),
-    BlankL,
-    MalformedL(Malformed)
+// the commandline: conversion options, plus what to convert and how to
+// treat the results
+#[derive(Default)]
+struct Args {
+    opts: Options,
+    input: Option<PathBuf>,
+    header_file: Option<PathBuf>,
+    footer_file: Option<PathBuf>,
+    fail_on_empty: bool,
+    recursive: Option<PathBuf>,
+    out_dir: Option<PathBuf>,
+    force: bool,
+    manifest: Option<PathBuf>,
+    count_only: bool,
+    slug_from_filename: bool,
+    split_on_h1: bool,
+    jsonl: bool,
+    oneline: bool,
+    html: bool,
+    print_title: bool,
+    help: bool
 }
 
-#[derive(Debug)]
-enum Block {
-    PreformattedB(Vec<String>),
-    ParaB(String),
-    LinksB(Vec<Link>),
-    HeadingB(Heading)
+// one converted file, as listed in the manifest
+struct ManifestEntry {
+    source: PathBuf,
+    output: PathBuf,
+    summary: Summary
 }
 
-// wrappers for including line numbers
-struct NumString(String, usize);
-struct NumLine(Line, usize);
-
-impl From<String> for Line {
-    // corresponds to OCaml function 'line_of_string : string -> line'
-    fn from(s: String) -> Self {
-        use Line::*;
-        use Malformed::*;
-        use HeadingLevel::*;
-
-        match s.chars().collect::<Vec<char>>()[..] {
-            // links
-            ['=', '>']               => MalformedL(MLink),
-            ['=', '>', ' ', ..]      => link_of_line(s),
-            ['=', '>', ..]           => MalformedL(MLink),
-
-            // headings
-            ['#', '#', '#']          => MalformedL(MHeading),
-            ['#', '#', '#', ' ']     => MalformedL(MHeading),
-            ['#', '#', '#', ' ', ..] => make_heading(s, H3, 4),
-            ['#', '#', '#', ..]      => make_heading(s, H3, 3),
-
-            ['#', '#', _]            => MalformedL(MHeading),
-            ['#', '#']               => MalformedL(MHeading),
-            ['#', '#', ' ', ..]      => make_heading(s, H2, 3),
-            ['#', '#', ..]           => MalformedL(MHeading),
-
-            ['#', ' ']               => MalformedL(MHeading),
-            ['#']                    => MalformedL(MHeading),
-            ['#', ' ', ..]           => make_heading(s, H1, 2),
-            ['#', ..]                => MalformedL(MHeading),
-
-            // paragraphs / blanks
-            []                       => BlankL,
-            _                        => ParaL(trim(s))
-        }
+// failures come with the exit status they call for
+fn convert_file(src: &Path, dst: &Path, args: &Args) -> Result<Summary, (u8, String)> {
+    let io_error = |e: io::Error| (EXIT_IO, e.to_string());
+    if dst.exists() && !args.force {
+        return Err((EXIT_IO, format!("{} already exists (use --force to overwrite)",
+                                     dst.display())));
     }
-}
 
-impl fmt::Display for Heading {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} {}", heading_chars(&self.0), self.1)
+    let input = File::open(src).map_err(io_error)?;
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent).map_err(io_error)?;
     }
-}
+    let mut output = BufWriter::new(File::create(dst).map_err(io_error)?);
+
+    let opts = opts_for_file(src, args);
+    let result = match convert(Box::new(BufReader::new(input)), &mut output, &opts) {
+        Ok(s) if args.fail_on_empty && !s.wrote_any =>
+            Err((EXIT_PARSE, "input is empty".to_string())),
+        Ok(s) => Ok(s),
+        Err(e) => Err((exit_status(&e), e.to_string()))
+    };
 
-impl fmt::Display for Link {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let Link(url, tag) = self.clone();
-        let caption = match tag {
-            Some(c) => c,
-            None => url.clone()
-        };
+    // don't leave a half-written file behind to be mistaken for a good one
+    if result.is_err() {
+        let _ = fs::remove_file(dst);
+    }
 
-        write!(f, "* [{}]({})\n", caption, url)
+    result
+}
+
+// the options, plus whatever is to be drawn from the file's name
+fn opts_for_file(path: &Path, args: &Args) -> Options {
+    let mut opts = args.opts.clone();
+    if args.slug_from_filename {
+        opts.slug = path.file_stem().map(|s| slugify(&s.to_string_lossy()));
     }
+    opts
 }
 
-impl fmt::Display for Block {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use Block::*;
+// write each section to its own file in dst, named for its heading
+fn write_sections(sections: Vec<Section>, dst: &Path, args: &Args) -> Result<(), String> {
+    fs::create_dir_all(dst).map_err(|e| format!("{}: {}", dst.display(), e))?;
+    let mut used = HashSet::new();
 
-        let s = match self {
-            ParaB(p)            => format!("{}\n\n", p),
-            PreformattedB(prpr) => format!("```\n{}\n```\n\n", prpr.join("\n")),
-            LinksB(ll)          => string_of_links(ll.to_vec()),
-            HeadingB(h)         => format!("{}\n\n", h)
+    for section in sections {
+        let base = match &section.title {
+            Some(t) if !slugify(t).is_empty() => slugify(t),
+            Some(_) => "section".to_string(),
+            None => "preamble".to_string()
         };
+        let mut name = base.clone();
+        let mut n = 1;
+        while !used.insert(name.clone()) {
+            n += 1;
+            name = format!("{}-{}", base, n);
+        }
 
-        write!(f, "{}", s)
+        let path = dst.join(format!("{}.md", name));
+        if path.exists() && !args.force {
+            return Err(format!("{} already exists (use --force to overwrite)",
+                               path.display()));
+        }
+        fs::write(&path, section.markdown)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
     }
-}
 
-fn heading_chars(h: &HeadingLevel) -> String {
-    use HeadingLevel::*;
+    Ok(())
+}
 
-    let s = match h {
-        H1 => "#",
-        H2 => "##",
-        H3 => "###"
-    };
+// every .gmi file under dir, in a stable order
+fn find_gmi_files(dir: &Path, acc: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_gmi_files(&path, acc)?;
+        } else if path.extension().is_some_and(|e| e == "gmi") {
+            acc.push(path);
+        }
+    }
 
-    s.to_string()
+    Ok(())
 }
 
-fn string_of_links(ll: Vec<Link>) -> String {
-    if ll.is_empty() {
-        return String::from("");
+// convert each .gmi file under src to a .md file at the mirrored path
+// under dst, carrying on past failures and reporting them at the end; the
+// exit status is the highest any failure called for
+fn convert_tree(src: &Path, dst: &Path, args: &Args) -> Result<(), u8> {
+    let mut files = vec![];
+    find_gmi_files(src, &mut files)
+        .map_err(|e| { eprintln!("gemtext2md: {}: {}", src.display(), e); EXIT_IO })?;
+
+    let mut converted = vec![];
+    let mut failures = vec![];
+    for file in files {
+        let rel = file.strip_prefix(src).unwrap();
+        let out = dst.join(rel).with_extension("md");
+        match convert_file(&file, &out, args) {
+            Ok(summary) => converted.push(ManifestEntry {
+                source: file, output: out, summary
+            }),
+            Err(e) => failures.push((file, e))
+        }
     }
 
-    let links: Vec<String> = ll.into_iter()
-        .map(|l| l.to_string()).collect();
-
-    format!("{}\n", links.join(""))
-}
+    if let Some(m) = &args.manifest {
+        fs::write(m, json_of_manifest(&converted))
+            .map_err(|e| { eprintln!("gemtext2md: {}: {}", m.display(), e); EXIT_IO })?;
+    }
 
-fn link_of_line(line: String) -> Line {
-    use Line::*;
-
-    let parts: Vec<&str> = line.splitn(3, " ").collect();
-    match parts.as_slice() {
-        [ "=>", "" ] => MalformedL(Malformed::MLink),
-        [ "=>", url ] => LinkL(Link(url.to_string(), None)),
-        [ "=>", url, tag ] => LinkL(
-            Link(url.to_string(), Some(tag.to_string()))
-        ),
-        _ => MalformedL(Malformed::MLink)
+    if failures.is_empty() {
+        return Ok(());
     }
-}
 
-fn trim(s: String) -> String { s.to_string().trim().to_string() }
+    eprintln!("gemtext2md: {} file(s) failed:", failures.len());
+    let status = failures.iter().map(|(_, (status, _))| *status).max().unwrap_or(EXIT_IO);
+    for (file, (_, e)) in failures {
+        eprintln!("  {}: {}", file.display(), e);
+    }
 
-fn make_heading(s: String, level: HeadingLevel, offset: usize) -> Line {
-    let trimmed = trim(s);
-    let after_hashes = &trimmed[offset..];
-    Line::HeadingL(Heading(level, after_hashes.to_string()))
+    Err(status)
 }
 
-/*
-
-Everything below this point is a five-stage pipeline.
+fn json_of_manifest(entries: &[ManifestEntry]) -> String {
+    let items: Vec<String> = entries.iter().map(|e| {
+        let title = match &e.summary.title {
+            Some(t) => json_string(t),
+            None => "null".to_string()
+        };
+        format!("  {{\"source\": {}, \"output\": {}, \"title\": {}, \"link_count\": {}}}",
+                json_string(&e.source.to_string_lossy()),
+                json_string(&e.output.to_string_lossy()),
+                title, e.summary.link_count)
+    }).collect();
+
+    if items.is_empty() {
+        return "[]\n".to_string();
+    }
 
-  read_lines:          get stdin input stream line by line as strings
-  gather_preformatted: tag the strings if they're in a preformatted block
-  decode_lines:        turn the strings into Lines
-  blocks_of_lines:     turn the Lines into Blocks
-  consume_blocks:      print the Blocks to stdout
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
 
-*/
+fn parse_kinds(s: &str) -> Result<Vec<BlockKind>, String> {
+    s.split(',').map(|k| match k {
+        "headings"     => Ok(BlockKind::Heading),
+        "paragraphs"   => Ok(BlockKind::Paragraph),
+        "links"        => Ok(BlockKind::Links),
+        "preformatted" => Ok(BlockKind::Preformatted),
+        "quotes"       => Ok(BlockKind::Quote),
+        "lists"        => Ok(BlockKind::List),
+        "comments"     => Ok(BlockKind::Comment),
+        "raw"          => Ok(BlockKind::Raw),
+        _ => Err(format!("unknown block type: {}", k))
+    }).collect()
+}
 
-fn read_lines(tx: Sender<NumString>) {
-    thread::spawn(move || {
-        let stdin = io::stdin();
-        for (lineno, line) in stdin.lock().lines().enumerate() {
-            match line {
-                Ok(l) => tx.send(NumString(l, lineno)).unwrap(),
-                Err(e) => panic!("couldn't read line {}, {}", lineno, e)
-            }
-        }
-    });
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))
 }
 
-// annotate lines with whether they occur within preformatted blocks
-// discard the lines beginning with "```"
-fn gather_preformatted(rx: Receiver<NumString>,
-                       tx: Sender<(bool, NumString)>) {
-    thread::spawn(move || {
-        let mut pref = false;
-
-        for i in rx {
-            match i.0.get(..3) {
-                Some("```") => pref = !pref,
-                _ => tx.send((pref, i)).unwrap()
-            }
-        }
-    });
+fn parse_number(name: &str, s: &str, max: usize) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n <= max => Ok(n),
+        _ => Err(format!("{} expects a number from 0 to {}, not {}", name, max, s))
+    }
 }
 
-// convert the incoming strings to Lines
-fn decode_lines(rx: Receiver<(bool, NumString)>,
-                tx: Sender<NumLine>) {
-    thread::spawn(move || {
-        use Line::PreformattedL;
-        let mut pref_acc: Vec<String> = vec![];
-
-        for (pref, ns) in rx {
-            let NumString(s, lineno) = ns;
-            if pref {
-                pref_acc.push(s);
-            } else {
-                if !pref_acc.is_empty() {
-                    tx.send(NumLine(PreformattedL(pref_acc.clone()), lineno)).
-                        unwrap();
-                    pref_acc.clear();
+// options taking a value accept both "--opt value" and "--opt=value"
+fn parse_args(args: Vec<String>) -> Result<Args, String> {
+    let mut cli = Args::default();
+    let opts = &mut cli.opts;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((n, v)) if n.starts_with("--") => (n.to_string(), Some(v.to_string())),
+            _ => (arg.clone(), None)
+        };
+        let mut value = || inline.clone().or_else(|| args.next())
+            .ok_or(format!("option {} needs a value", name));
+
+        match name.as_str() {
+            "--help" | "-h" => {
+                cli.help = true;
+                return Ok(cli);
+            },
+            "--normalize-urls" => opts.normalize_urls = true,
+            "--warn-dup-headings" => opts.warn_dup_headings = true,
+            "--exact-dup-headings" => opts.exact_dup_headings = true,
+            "--fail-on-empty" => cli.fail_on_empty = true,
+            "--pre-alt" => opts.pre_alt = match value()?.as_str() {
+                "drop"    => PreAlt::Drop,
+                "info"    => PreAlt::Info,
+                "comment" => PreAlt::Comment,
+                "caption" => PreAlt::Caption,
+                v => return Err(format!("--pre-alt expects info, comment, caption or drop, not {}", v))
+            },
+            "--pre-caption" => opts.pre_alt = PreAlt::Caption,
+            "--lead-paragraph" => opts.lead_paragraph = match value()?.as_str() {
+                "none"        => LeadParagraph::Unmarked,
+                "frontmatter" => LeadParagraph::Frontmatter,
+                "class"       => LeadParagraph::Class,
+                v => return Err(format!("--lead-paragraph expects frontmatter, class or none, not {}", v))
+            },
+            "--no-label" => opts.no_label = match value()?.as_str() {
+                "url"  => NoLabel::Url,
+                "path" => NoLabel::Path,
+                v => match v.strip_prefix("fixed:") {
+                    Some(t) => NoLabel::Fixed(t.to_string()),
+                    None => return Err(format!("--no-label expects url, path or fixed:TEXT, not {}", v))
                 }
-                tx.send(NumLine(Line::from(s), lineno)).unwrap();
-            }
+            },
+            "--links" => opts.links = match value()?.as_str() {
+                "markdown" => LinkStyle::Markdown,
+                "text"     => LinkStyle::Text,
+                v => return Err(format!("--links expects markdown or text, not {}", v))
+            },
+            "--recursive" => cli.recursive = Some(PathBuf::from(value()?)),
+            "--out-dir" => cli.out_dir = Some(PathBuf::from(value()?)),
+            "--force" => cli.force = true,
+            "--manifest" => cli.manifest = Some(PathBuf::from(value()?)),
+            "--count-only" => cli.count_only = true,
+            "--jsonl" => cli.jsonl = true,
+            "--oneline" => cli.oneline = true,
+            "--html" => cli.html = true,
+            "--html-source-attrs" => opts.html_source_attrs = true,
+            "--print-title" => cli.print_title = true,
+            "--keep-close-alt" => opts.keep_close_alt = true,
+            "--compact" => opts.compact = true,
+            "--lint-links" => opts.lint_links = true,
+            "--simplify-links" => opts.simplify_links = true,
+            "--stream" | "--no-buffer" => opts.stream = true,
+            "--lenient-headings" => opts.lenient_headings = true,
+            "--quiet" => opts.quiet = true,
+            "--embedded" => opts.embedded = true,
+            "--scheme-badge" => opts.scheme_badge = true,
+            "--autolink-bare-urls" => opts.autolink_bare_urls = true,
+            "--validate" => opts.validate = true,
+            "--unescape-labels" => opts.unescape_labels = true,
+            "--strip-ansi" => opts.strip_ansi = true,
+            "--link-titles" => opts.link_titles = true,
+            "--trim-pre-trailing" => opts.trim_pre_trailing = true,
+            "--detect-lang" => opts.detect_lang = true,
+            "--number-headings" => opts.number_headings = true,
+            "--slug-from-filename" => cli.slug_from_filename = true,
+            "--split-on-h1" => cli.split_on_h1 = true,
+            "--dotslash" => opts.dotslash = true,
+            "--pre-to-table" => opts.pre_to_table = true,
+            "--toc" => opts.toc = true,
+            "--closed-headings" => opts.closed_headings = true,
+            "--mark-malformed" => opts.mark_malformed = true,
+            "--no-links" => opts.no_links = true,
+            "--indent-code" => opts.indent_code = true,
+            "--code-indent" => opts.code_indent = match value()?.as_str() {
+                "spaces" => CodeIndent::Spaces,
+                "tab"    => CodeIndent::Tab,
+                v => return Err(format!("--code-indent expects spaces or tab, not {}", v))
+            },
+            "--header-file" => cli.header_file = Some(PathBuf::from(value()?)),
+            "--footer-file" => cli.footer_file = Some(PathBuf::from(value()?)),
+            "--encoding" => opts.encoding = match value()?.to_lowercase().as_str() {
+                "utf8" | "utf-8"          => Encoding::Utf8,
+                "cp1252" | "windows-1252" => Encoding::Cp1252,
+                v => return Err(format!("unsupported encoding: {}", v))
+            },
+            "--source-fence" => opts.source_fence = match value()? {
+                v if v.is_empty() => return Err("--source-fence can't be empty".to_string()),
+                v => Some(v)
+            },
+            "--eol" => opts.eol = match value()?.as_str() {
+                "lf"   => Eol::Lf,
+                "crlf" => Eol::Crlf,
+                v => return Err(format!("--eol expects lf or crlf, not {}", v))
+            },
+            "--only" => opts.only = Some(parse_kinds(&value()?)?),
+            // four spaces would make the list an indented code block
+            "--list-indent" => opts.list_indent = parse_number(&name, &value()?, 3)?,
+            "--toc-depth" =>
+                opts.toc_depth = Some(parse_number(&name, &value()?, 3)?),
+            "--toc-indent" =>
+                opts.toc_indent = Some(parse_number(&name, &value()?, 8)?),
+            "--reset-pre-on-blank" => opts.reset_pre_on_blank =
+                match parse_number(&name, &value()?, 100)? {
+                    0 => return Err(format!("{} expects a number from 1 to 100", name)),
+                    n => Some(n)
+                },
+            "--warn-link-run" =>
+                opts.warn_link_run = Some(parse_number(&name, &value()?, 100_000)?),
+            "--provenance" => if opts.provenance.is_none() {
+                opts.provenance = Some(String::from("converted from text/gemini by gemtext2md"));
+            },
+            "--provenance-text" => opts.provenance = Some(value()?),
+            "--text-schemes" =>
+                opts.text_schemes = value()?.split(',').map(String::from).collect(),
+            "--wrap" => opts.wrap = Some(parse_number(&name, &value()?, 10_000)?),
+            "--wrap-indent" => opts.wrap_indent = parse_number(&name, &value()?, 3)?,
+            "--squeeze-spaces" => opts.squeeze_spaces = true,
+            "--progress" => opts.progress = opts.progress.or(Some(10_000)),
+            "--progress-every" =>
+                opts.progress = match parse_number(&name, &value()?, 1_000_000_000)? {
+                    0 => return Err(format!("{} expects a number of lines above 0", name)),
+                    n => Some(n)
+                },
+            "--infer-headings" =>
+                opts.infer_headings = opts.infer_headings.or(Some(40)),
+            "--infer-headings-max" =>
+                opts.infer_headings = Some(parse_number(&name, &value()?, 1_000)?),
+            "--preserve-empty-links" => opts.preserve_empty_links = true,
+            "--smart-links" => opts.smart_links = true,
+            "--no-blank-between-links-and-heading" => opts.tight_heading_links = true,
+            "--no-lists" => opts.no_lists = true,
+            "--links-frontmatter" =>
+                opts.links_frontmatter = opts.links_frontmatter.or(Some(LinksFrontmatter::Urls)),
+            "--links-frontmatter-labels" =>
+                opts.links_frontmatter = Some(LinksFrontmatter::Labelled),
+            "--dedup-links" => opts.dedup_links = true,
+            "--strip-trailing-links" => opts.strip_trailing_links = true,
+            "--sanitize-control" => opts.sanitize_control = match value()?.as_str() {
+                "drop" => Some(Sanitize::Drop),
+                "replace" => Some(Sanitize::Replace),
+                v => return Err(format!("--sanitize-control expects drop or replace, not {}", v))
+            },
+            "--sanitize-pre" => opts.sanitize_pre = true,
+            "--trim-heading-trailing-hashes" => opts.trim_heading_hashes = true,
+            "--canonical" => opts.canonical = true,
+            "--literal-fences" => opts.literal_fences = true,
+            "--byte-offsets" => opts.byte_offsets = true,
+            "--sentence-breaks" => opts.sentence_breaks = true,
+            "--embed-source" => opts.embed_source = true,
+            "--subtitle" => opts.subtitle = true,
+            "--preserve-link-whitespace" => opts.preserve_link_whitespace = true,
+            "--show-urls" => opts.show_urls = opts.show_urls.or(Some(ShowUrls::Inside)),
+            "--show-urls-after" => opts.show_urls = Some(ShowUrls::After),
+            "--escape" => opts.escape = true,
+            "--numbered-links" =>
+                opts.numbered_links = opts.numbered_links.or(Some(LinkNumbering::PerBlock)),
+            "--continue-numbering" => opts.numbered_links = Some(LinkNumbering::Continuous),
+            "--embed-under" => opts.embed_under = match parse_number(&name, &value()?, 6)? {
+                0 => return Err(format!("{} expects a level from 1 to 6", name)),
+                n => Some(n)
+            },
+            "--max-blocks" =>
+                opts.max_blocks = Some(parse_number(&name, &value()?, 1_000_000)?),
+            "--section" =>
+                opts.section = Some(parse_number(&name, &value()?, 100_000)?),
+            // with no blank line, consecutive paragraphs would run together
+            "--block-spacing" => opts.block_spacing = match parse_number(&name, &value()?, 8)? {
+                0 => return Err(format!("{} expects a number from 1 to 8", name)),
+                n => Some(n)
+            },
+            _ if !arg.starts_with('-') && cli.input.is_none() =>
+                cli.input = Some(PathBuf::from(arg)),
+            _ if !arg.starts_with('-') => return Err(format!("unexpected argument: {}", arg)),
+            _ => return Err(format!("unrecognised option: {}", arg))
         }
+    }
 
-        if !pref_acc.is_empty() {
-            tx.send(NumLine(PreformattedL(pref_acc), 0)).unwrap();
+    if cli.split_on_h1 {
+        if cli.out_dir.is_none() || cli.recursive.is_some() {
+            return Err("--split-on-h1 needs --out-dir, and not --recursive".to_string());
         }
-    });
+    } else if cli.recursive.is_some() != cli.out_dir.is_some() {
+        return Err("--recursive and --out-dir must be used together".to_string());
+    }
+    if cli.manifest.is_some() && cli.recursive.is_none() {
+        return Err("--manifest only makes sense with --recursive".to_string());
+    }
+    if cli.input.is_some() && cli.recursive.is_some() {
+        return Err("an input file can't be given with --recursive".to_string());
+    }
+
+    Ok(cli)
 }
 
-// aggregate Lines into Blocks
-fn blocks_of_lines(rx: Receiver<NumLine>,
-                   tx: Sender<Block>) {
-    thread::spawn(move || {
-        use Block::*;
-        use Line::*;
-
-        let mut links: Vec<Link> = vec![];
-
-        for NumLine(line, lineno) in rx {
-            let (flush_links, payload) = match line {
-                MalformedL(m)    => panic!("error: {:?} at line {}", m, lineno),
-                LinkL(link)      => { links.push(link); (false, None) },
-                BlankL           => (true, None),
-                ParaL(p)         => (true, Some(ParaB(p))),
-                HeadingL(h)      => (true, Some(HeadingB(h))),
-                PreformattedL(p) => (true, Some(PreformattedB(p)))
-            };
-
-            if flush_links {
-                tx.send(LinksB(links.clone())).unwrap();
-                links.clear();
-            }
+fn main() -> ExitCode {
+    let mut args = match parse_args(env::args().skip(1).collect()) {
+        Ok(a) => a,
+        Err(e) => return fail(EXIT_USAGE, e)
+    };
+    if args.help {
+        print!("{}", HELP);
+        return ExitCode::SUCCESS;
+    }
 
-            if let Some(p) = payload {
-                tx.send(p).unwrap();
+    // read only once the options are known to be good, as failing to read
+    // them is an I/O error rather than a usage error
+    for (path, text) in [(&args.header_file, &mut args.opts.header),
+                         (&args.footer_file, &mut args.opts.footer)] {
+        if let Some(path) = path {
+            match read_file(path) {
+                Ok(t) => *text = Some(t),
+                Err(e) => return fail(EXIT_IO, e)
             }
         }
+    }
 
-        if !links.is_empty() {
-            tx.send(LinksB(links)).unwrap();
+    if let (Some(src), Some(dst)) = (&args.recursive, &args.out_dir) {
+        return match convert_tree(src, dst, &args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(status) => ExitCode::from(status)
+        };
+    }
+
+    let (input, opts): (Box<dyn BufRead + Send>, Options) = match &args.input {
+        // the slug, if wanted, comes from the last part of the URL's path
+        #[cfg(feature = "fetch")]
+        Some(path) if fetch::is_url(&path.to_string_lossy()) =>
+            match fetch::fetch(&path.to_string_lossy()) {
+                Ok(body) => (body, opts_for_file(path, &args)),
+                Err(e) => return fail(EXIT_IO, e)
+            },
+        Some(path) => match File::open(path) {
+            Ok(f) => (Box::new(BufReader::new(f)), opts_for_file(path, &args)),
+            Err(e) => return fail(EXIT_IO, format!("{}: {}", path.display(), e))
+        },
+        None => {
+            if args.slug_from_filename {
+                warn(&args.opts, "--slug-from-filename needs an input file, \
+                                  so there is no slug".to_string());
+            }
+            (Box::new(BufReader::new(io::stdin())), args.opts.clone())
         }
-    });
-}
+    };
 
-fn consume_blocks(rx: Receiver<Block>) -> Result<(), ()>{
-    for i in rx {
-        print!("{}", i);
-        io::stdout().flush().unwrap();
+    if let (true, Some(dst)) = (args.split_on_h1, &args.out_dir) {
+        let sections = match split_on_h1(input, &opts) {
+            Ok(s) => s,
+            Err(e) => return fail(exit_status(&e), e)
+        };
+        return match write_sections(sections, dst, &args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => fail(EXIT_IO, e)
+        };
     }
 
-    Ok(())
-}
-
-fn main() -> Result<(), ()> {
-    let (tx1, rx1) = mpsc::channel();
-    read_lines(tx1);
+    if args.print_title {
+        return match title(input, &opts) {
+            Ok(Some(t)) => { println!("{}", t); ExitCode::SUCCESS },
+            Ok(None) => ExitCode::from(EXIT_PARSE),
+            Err(e) => fail(exit_status(&e), e)
+        };
+    }
 
-    let (tx2, rx2) = mpsc::channel();
-    gather_preformatted(rx1, tx2);
+    if args.count_only {
+        return match count(input, &opts) {
+            Ok(c) => { println!("{}", c); ExitCode::SUCCESS },
+            Err(e) => fail(exit_status(&e), e)
+        };
+    }
 
-    let (tx3, rx3) = mpsc::channel();
-    decode_lines(rx2, tx3);
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    if args.jsonl {
+        if let Err(e) = convert_jsonl(input, &mut stdout, &opts) {
+            let _ = stdout.flush();
+            return fail(exit_status(&e), e);
+        }
+        return ExitCode::SUCCESS;
+    }
+    if args.html {
+        if let Err(e) = convert_html(input, &mut stdout, &opts) {
+            let _ = stdout.flush();
+            return fail(exit_status(&e), e);
+        }
+        return ExitCode::SUCCESS;
+    }
+    if args.oneline {
+        if let Err(e) = convert_oneline(input, &mut stdout, &opts) {
+            let _ = stdout.flush();
+            return fail(exit_status(&e), e);
+        }
+        return ExitCode::SUCCESS;
+    }
 
-    let (tx4, rxlast) = mpsc::channel();
-    blocks_of_lines(rx3, tx4);
+    let summary = match convert(input, &mut stdout, &opts) {
+        Ok(s) => s,
+        Err(e) => {
+            // whatever was converted before the error still goes out
+            let _ = stdout.flush();
+            return fail(exit_status(&e), e);
+        }
+    };
+    if args.fail_on_empty && !summary.wrote_any {
+        return fail(EXIT_PARSE, "input is empty");
+    }
 
-    consume_blocks(rxlast)
+    ExitCode::SUCCESS
 }